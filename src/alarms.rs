@@ -0,0 +1,685 @@
+//! Alarms raised by control loops when something goes wrong, e.g. a fan which does not spin. The
+//! [supervisors][Supervisor] of a loop look at its [state][LoopState] after every sample; when one
+//! of them raises (or resolves) an alarm, the loop logs it, reports it as an `Alarm` monitoring
+//! message and runs the hook command of the alarm, if any. While an alarm lasts, its supervisor can
+//! also take over the output of the loop with a failsafe value.
+
+use crate::operations::parameters::BoxedIterator;
+use crate::pipeline::LoopState;
+use crate::sample::Sample;
+use crate::validation::{Checks, Validate, ValidationError};
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// A change of state of an alarm.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlarmEvent {
+    /// Kind of alarm, e.g. `stall`
+    pub alarm: String,
+    /// Whether the alarm was raised (or resolved)
+    pub active: bool,
+    /// Time of the sample at which the alarm changed, in seconds
+    pub time: f64,
+    /// Values the alarm is about (e.g. `duty` and `rpm`)
+    #[serde(flatten)]
+    pub values: BTreeMap<String, f64>,
+    /// Command to run for this event
+    #[serde(skip)]
+    pub hook: Option<String>,
+}
+
+impl AlarmEvent {
+    /// Log the event, report it through `tx` as an `Alarm` of `id` and run its hook (without
+    /// waiting for it to finish).
+    pub fn report(&self, id: &str, tx: Option<&mpsc::Sender<String>>) {
+        let values = self
+            .values
+            .iter()
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if self.active {
+            error!("Alarm {} raised at {}s: {}", id, self.time, values);
+        } else {
+            info!("Alarm {} resolved at {}s: {}", id, self.time, values);
+        }
+        if let Some(tx) = tx {
+            let serialized = serde_json::to_string(self).unwrap();
+            tx.send(format!("{}: Alarm: {}\n", id, serialized))
+                .expect("Failed to send data to monitor; main thread must have crashed.");
+        }
+        if let Some(hook) = &self.hook {
+            run_hook(hook, id, self);
+        }
+    }
+}
+
+/// Run `cmd` with `sh -c` on a new thread, with the event in its environment: `FAND_ALARM` (the id
+/// of the alarm), `FAND_STATE` (`raised` or `resolved`), `FAND_TIME` and every value of the event
+/// in upper case, prefixed by `FAND_` (e.g. `FAND_RPM`).
+fn run_hook(cmd: &str, id: &str, event: &AlarmEvent) {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env("FAND_ALARM", id)
+        .env(
+            "FAND_STATE",
+            if event.active { "raised" } else { "resolved" },
+        )
+        .env("FAND_TIME", event.time.to_string());
+    for (name, value) in &event.values {
+        command.env(format!("FAND_{}", name.to_uppercase()), value.to_string());
+    }
+    let cmd = cmd.to_string();
+    thread::spawn(move || match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Hook `{}` failed ({})", cmd, status),
+        Err(err) => error!("Failed to run hook `{}`: {}", cmd, err),
+    });
+}
+
+/// Watches the state of a control loop after every sample.
+pub trait Supervisor: Send {
+    /// Look at the state of the loop after a sample; returns the alarms raised or resolved (and
+    /// the actions taken), if any.
+    fn observe(&mut self, state: &LoopState) -> Vec<AlarmEvent>;
+
+    /// The value to push to the output instead of the one of the pipeline, if any.
+    fn failsafe(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// A condition which must hold for some time before it counts, e.g. to ignore a fan which takes
+/// a few seconds to spin up.
+#[derive(Debug, Clone, Default)]
+pub struct Debounced {
+    /// Time for which the condition must hold, in seconds
+    duration: f64,
+    /// Number of consecutive samples for which the condition must hold
+    samples: u32,
+    /// Time since which the condition holds
+    since: Option<f64>,
+    /// Number of consecutive samples for which the condition held so far
+    count: u32,
+    active: bool,
+}
+
+impl Debounced {
+    pub fn new(duration: f64) -> Debounced {
+        Debounced {
+            duration,
+            ..Debounced::default()
+        }
+    }
+
+    /// Also require the condition to hold for `samples` consecutive samples.
+    pub fn samples(mut self, samples: u32) -> Debounced {
+        self.samples = samples;
+        self
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Whether the condition holds at `time`; returns the new state when it changes, i.e.
+    /// `Some(true)` once it held for long enough and `Some(false)` as soon as it stops holding.
+    pub fn update(&mut self, time: f64, holds: bool) -> Option<bool> {
+        if !holds {
+            self.since = None;
+            self.count = 0;
+            return if self.active {
+                self.active = false;
+                Some(false)
+            } else {
+                None
+            };
+        }
+        let since = *self.since.get_or_insert(time);
+        self.count = self.count.saturating_add(1);
+        if !self.active && time - since >= self.duration && self.count >= self.samples {
+            self.active = true;
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+/// Detection of a stalled fan: the fan is considered stalled when its duty cycle (the last value
+/// pushed to the output) is at least `min_duty` but its tachometer reads less than `min_rpm` (or
+/// less than `tolerance` times the `expected` speed) for `duration` seconds. Requires the
+/// pipeline to have a [tachometer][crate::pipeline::Pipeline::tachometer].
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct StallParameters {
+    /// Duty cycle (in %) from which the fan must spin (20 by default)
+    #[serde(default = "default_min_duty")]
+    pub min_duty: f64,
+    /// Speed (in RPM) under which the fan is considered stopped (100 by default)
+    #[serde(default = "default_min_rpm")]
+    pub min_rpm: f64,
+    /// Expected speed of the fan: a list of `[duty, rpm]` pairs sorted by duty, interpolated
+    /// linearly (none by default)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expected: Vec<(f64, f64)>,
+    /// Fraction of the expected speed under which the fan is considered stalled (0.5 by default)
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    /// Time for which the fan must be stalled before raising the alarm, in seconds (10 by
+    /// default)
+    #[serde(default = "default_duration")]
+    pub duration: f64,
+    /// Command run (with `sh -c`) when the alarm is raised
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+    /// Duty cycle pushed to the output instead of the values of the pipeline while the alarm lasts
+    /// (e.g. 100, to try to restart the fan); by default the output is left alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failsafe: Option<f64>,
+}
+
+fn default_min_duty() -> f64 {
+    20.0
+}
+
+fn default_min_rpm() -> f64 {
+    100.0
+}
+
+fn default_tolerance() -> f64 {
+    0.5
+}
+
+fn default_duration() -> f64 {
+    10.0
+}
+
+impl Validate for StallParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .finite("min_duty", self.min_duty)
+            .non_negative("min_rpm", self.min_rpm)
+            .positive("tolerance", self.tolerance)
+            .non_negative("duration", self.duration);
+        let sorted = self.expected.windows(2).all(|pair| pair[0].0 <= pair[1].0);
+        checks.require(sorted, "expected", "must be sorted by duty");
+        if let Some(failsafe) = self.failsafe {
+            // a lower duty cycle would resolve the alarm as soon as it is pushed
+            checks.require(
+                failsafe >= self.min_duty,
+                "failsafe",
+                "must not be lower than min_duty",
+            );
+        }
+        checks.into_errors()
+    }
+}
+
+impl StallParameters {
+    /// The expected speed of the fan at `duty`, if given.
+    pub fn expected_rpm(&self, duty: f64) -> Option<f64> {
+        let first = self.expected.first()?;
+        let last = self.expected.last()?;
+        if duty <= first.0 {
+            return Some(first.1);
+        }
+        if duty >= last.0 {
+            return Some(last.1);
+        }
+        let index = self.expected.partition_point(|(at, _)| *at <= duty);
+        let ((d0, r0), (d1, r1)) = (self.expected[index - 1], self.expected[index]);
+        Some(r0 + (r1 - r0) * (duty - d0) / (d1 - d0))
+    }
+
+    /// Whether a fan at `duty` spinning at `rpm` counts as stalled.
+    pub fn is_stalled(&self, duty: f64, rpm: f64) -> bool {
+        duty >= self.min_duty
+            && (rpm < self.min_rpm
+                || self
+                    .expected_rpm(duty)
+                    .is_some_and(|expected| rpm < self.tolerance * expected))
+    }
+}
+
+/// Raises the `stall` alarm of a control loop (see [StallParameters]).
+pub struct StallDetector {
+    params: StallParameters,
+    stalled: Debounced,
+}
+
+impl StallDetector {
+    pub fn new(params: StallParameters) -> StallDetector {
+        StallDetector {
+            stalled: Debounced::new(params.duration),
+            params,
+        }
+    }
+}
+
+impl StallDetector {
+    fn check(&mut self, state: &LoopState) -> Option<AlarmEvent> {
+        let time = state.last_sample?.time;
+        let duty = state.last_pushed.unwrap_or(0.0);
+        // an unreadable tachometer is reported as such, not as a stalled fan
+        let stalled = state
+            .last_rpm
+            .is_some_and(|rpm| self.params.is_stalled(duty, rpm));
+        let active = self.stalled.update(time, stalled)?;
+        let mut values = BTreeMap::new();
+        values.insert("duty".to_string(), duty);
+        if let Some(rpm) = state.last_rpm {
+            values.insert("rpm".to_string(), rpm);
+        }
+        Some(AlarmEvent {
+            alarm: "stall".to_string(),
+            active,
+            time,
+            values,
+            hook: self.params.hook.clone().filter(|_| active),
+        })
+    }
+}
+
+impl Supervisor for StallDetector {
+    fn observe(&mut self, state: &LoopState) -> Vec<AlarmEvent> {
+        self.check(state).into_iter().collect()
+    }
+
+    fn failsafe(&self) -> Option<f64> {
+        self.params.failsafe.filter(|_| self.stalled.is_active())
+    }
+}
+
+/// An action taken when the source of a pipeline stays above its critical value (see
+/// [OverTemperatureParameters]).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct EmergencyAction {
+    /// Time for which the alarm must have lasted before taking this action, in seconds (0 by
+    /// default, i.e. as soon as it is raised)
+    #[serde(default)]
+    pub after: f64,
+    /// Command run (with `sh -c`) to take the action, e.g. to throttle a workload or
+    /// `systemctl poweroff`; without it, the action is only reported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+}
+
+/// Emergency actions taken when the source of a pipeline (its input, or node `from`) stays above
+/// a critical value although the fan runs at full speed. The `over_temperature` alarm is raised
+/// once this lasted for `debounce` seconds; its actions are then taken in turn, each once it lasted
+/// for `after` more seconds, and again every `cooldown` seconds for as long as it lasts. An action
+/// is not taken again within `cooldown` seconds, even if the alarm was resolved in between.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct OverTemperatureParameters {
+    /// Value of the source above which the alarm is raised (e.g. in °C)
+    pub critical: f64,
+    /// Duty cycle (in %) from which the fan counts as running at full speed (99 by default, since
+    /// smoothing operations such as oscillators only approach 100 asymptotically; 0 to act whatever
+    /// the fan does)
+    #[serde(default = "default_full_duty")]
+    pub full_duty: f64,
+    /// Time for which the source must be above its critical value before raising the alarm, in
+    /// seconds (10 by default)
+    #[serde(default = "default_duration")]
+    pub debounce: f64,
+    /// Minimum time between two runs of the same action, in seconds (300 by default)
+    #[serde(default = "default_cooldown")]
+    pub cooldown: f64,
+    /// Actions to take, in order of escalation
+    #[serde(default)]
+    pub actions: Vec<EmergencyAction>,
+}
+
+fn default_full_duty() -> f64 {
+    99.0
+}
+
+fn default_cooldown() -> f64 {
+    300.0
+}
+
+impl Validate for OverTemperatureParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .finite("critical", self.critical)
+            .finite("full_duty", self.full_duty)
+            .non_negative("debounce", self.debounce)
+            .positive("cooldown", self.cooldown);
+        for (index, action) in self.actions.iter().enumerate() {
+            checks.non_negative(&format!("actions[{}].after", index), action.after);
+        }
+        checks.into_errors()
+    }
+}
+
+/// Raises the `over_temperature` alarm of a control loop and takes its actions (see
+/// [OverTemperatureParameters]).
+pub struct OverTemperature {
+    params: OverTemperatureParameters,
+    over: Debounced,
+    /// Time at which the alarm was raised, while it lasts
+    raised_at: Option<f64>,
+    /// Time at which each action was last taken
+    taken_at: Vec<Option<f64>>,
+}
+
+impl OverTemperature {
+    pub fn new(params: OverTemperatureParameters) -> OverTemperature {
+        OverTemperature {
+            over: Debounced::new(params.debounce),
+            raised_at: None,
+            taken_at: vec![None; params.actions.len()],
+            params,
+        }
+    }
+}
+
+impl Supervisor for OverTemperature {
+    fn observe(&mut self, state: &LoopState) -> Vec<AlarmEvent> {
+        let (time, input) = match (state.last_sample, state.last_input) {
+            (Some(sample), Some(input)) => (sample.time, input.value),
+            _ => return Vec::new(),
+        };
+        let duty = state.last_pushed.unwrap_or(0.0);
+        let over = input > self.params.critical && duty >= self.params.full_duty;
+        let mut values = BTreeMap::new();
+        values.insert("input".to_string(), input);
+        values.insert("duty".to_string(), duty);
+        let event = |values: BTreeMap<String, f64>, active, hook| AlarmEvent {
+            alarm: "over_temperature".to_string(),
+            active,
+            time,
+            values,
+            hook,
+        };
+        let change = self.over.update(time, over);
+        if change == Some(false) {
+            self.raised_at = None;
+            return vec![event(values, false, None)];
+        }
+        let raised_at = match (change, self.raised_at) {
+            (Some(true), _) => *self.raised_at.insert(time),
+            (None, Some(raised_at)) => raised_at,
+            _ => return Vec::new(),
+        };
+        let cooldown = self.params.cooldown;
+        let mut events = Vec::new();
+        for (index, action) in self.params.actions.iter().enumerate() {
+            let due = time - raised_at >= action.after
+                && self.taken_at[index].is_none_or(|taken_at| time - taken_at >= cooldown);
+            if due {
+                self.taken_at[index] = Some(time);
+                let mut values = values.clone();
+                values.insert("action".to_string(), index as f64);
+                events.push(event(values, true, action.hook.clone()));
+            }
+        }
+        if change == Some(true) && events.is_empty() {
+            events.push(event(values, true, None));
+        }
+        events
+    }
+}
+
+/// How a pipeline carries on when its input cannot be read: the last value read is used instead
+/// for up to `max_errors` consecutive failures. Beyond that, the `input_failure` alarm is raised
+/// and the `failsafe` duty cycle is pushed to the output until the input can be read again, or
+/// the loop stops if there is none. Without these parameters, the loop stops at the first failure.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct InputErrorParameters {
+    /// Number of consecutive failures for which the last value read is used instead (0 by
+    /// default)
+    #[serde(default)]
+    pub max_errors: u32,
+    /// Duty cycle pushed to the output after `max_errors` consecutive failures (e.g. 100, to cool
+    /// down blindly); by default the loop stops instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failsafe: Option<f64>,
+    /// Command run (with `sh -c`) when the failsafe takes over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+}
+
+impl Validate for InputErrorParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        match self.failsafe {
+            Some(failsafe) => {
+                checks.finite("failsafe", failsafe);
+            }
+            None => {
+                checks.require(
+                    self.hook.is_none(),
+                    "hook",
+                    "is only run when a failsafe takes over",
+                );
+            }
+        }
+        checks.into_errors()
+    }
+}
+
+/// Raises the `input_failure` alarm of a control loop and pushes its failsafe value (see
+/// [InputErrorParameters]).
+pub struct InputFailure {
+    params: InputErrorParameters,
+    active: bool,
+}
+
+impl InputFailure {
+    pub fn new(params: InputErrorParameters) -> InputFailure {
+        InputFailure {
+            params,
+            active: false,
+        }
+    }
+}
+
+impl Supervisor for InputFailure {
+    fn observe(&mut self, state: &LoopState) -> Vec<AlarmEvent> {
+        let time = match state.last_sample {
+            Some(sample) => sample.time,
+            None => return Vec::new(),
+        };
+        let active = state.input_errors > self.params.max_errors;
+        if active == self.active {
+            return Vec::new();
+        }
+        self.active = active;
+        let mut values = BTreeMap::new();
+        values.insert("errors".to_string(), f64::from(state.input_errors));
+        vec![AlarmEvent {
+            alarm: "input_failure".to_string(),
+            active,
+            time,
+            values,
+            hook: self.params.hook.clone().filter(|_| active),
+        }]
+    }
+
+    fn failsafe(&self) -> Option<f64> {
+        self.params.failsafe.filter(|_| self.active)
+    }
+}
+
+/// The last values of the stages of a pipeline, by id (as in monitoring messages).
+#[derive(Clone, Default)]
+pub struct StageValues(Arc<Mutex<BTreeMap<String, f64>>>);
+
+impl StageValues {
+    /// Keep the last value produced by `iter` as the value of `stage`.
+    pub fn tap(&self, iter: BoxedIterator, stage: String) -> BoxedIterator {
+        Box::new(Watched {
+            iter,
+            values: self.clone(),
+            stage,
+        })
+    }
+
+    pub fn get(&self, stage: &str) -> Option<f64> {
+        self.0.lock().unwrap().get(stage).copied()
+    }
+}
+
+struct Watched {
+    iter: BoxedIterator,
+    values: StageValues,
+    stage: String,
+}
+
+impl Iterator for Watched {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let sample = self.iter.next()?;
+        self.values
+            .0
+            .lock()
+            .unwrap()
+            .insert(self.stage.clone(), sample.value);
+        Some(sample)
+    }
+}
+
+/// When an [AlertRule] holds.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// The value of the stage is greater than the given one
+    Above(f64),
+    /// The value of the stage is lower than the given one
+    Below(f64),
+    /// The stage could not be read (only for stages `input` and `tachometer`)
+    Error,
+}
+
+/// A declarative alert: the alarm `name` is raised when the condition `when` holds for the values
+/// of `stage` for `for` seconds and `samples` consecutive samples, and resolved as soon as it no
+/// longer holds.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct AlertRule {
+    /// Name of the alarm, used as its id in monitoring messages (prefixed by `name/` for named
+    /// pipelines) and in `FAND_ALARM`
+    pub name: String,
+    /// Stage whose values are watched: `input` (the source of the pipeline), the index of an
+    /// operation, a node or `node/index` in a graph, `tachometer`, or `output` (the last value
+    /// pushed to the output)
+    pub stage: String,
+    pub when: Condition,
+    /// Time for which the condition must hold before raising the alarm, in seconds (0 by default)
+    #[serde(default, rename = "for")]
+    pub duration: f64,
+    /// Number of consecutive samples for which the condition must hold before raising the alarm
+    /// (1 by default)
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+    /// Command run (with `sh -c`) when the alarm is raised, with the value of the stage in
+    /// `FAND_VALUE`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+    /// Command run (with `sh -c`) when the alarm is resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<String>,
+}
+
+fn default_samples() -> u32 {
+    1
+}
+
+impl Validate for AlertRule {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .require(!self.name.is_empty(), "name", "must not be empty")
+            .require(!self.stage.is_empty(), "stage", "must not be empty")
+            .non_negative("for", self.duration);
+        match self.when {
+            Condition::Above(threshold) => {
+                checks.finite("when.above", threshold);
+            }
+            Condition::Below(threshold) => {
+                checks.finite("when.below", threshold);
+            }
+            Condition::Error => {
+                checks.require(
+                    self.stage == "input" || self.stage == "tachometer",
+                    "when",
+                    "only stages input and tachometer can fail",
+                );
+            }
+        }
+        checks.into_errors()
+    }
+}
+
+/// Raises the alarms of the [alert rules][AlertRule] of a control loop.
+pub struct Alerts {
+    rules: Vec<(AlertRule, Debounced)>,
+    values: StageValues,
+    /// Prefix of the ids of the stages (the name of the pipeline followed by `/`, if any)
+    prefix: String,
+}
+
+impl Alerts {
+    /// Watch the values of the stages in `values`, whose ids are prefixed by `prefix`.
+    pub fn new(rules: Vec<AlertRule>, values: StageValues, prefix: &str) -> Alerts {
+        Alerts {
+            rules: rules
+                .into_iter()
+                .map(|rule| {
+                    let debounced = Debounced::new(rule.duration).samples(rule.samples);
+                    (rule, debounced)
+                })
+                .collect(),
+            values,
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+impl Supervisor for Alerts {
+    fn observe(&mut self, state: &LoopState) -> Vec<AlarmEvent> {
+        let time = match state.last_sample {
+            Some(sample) => sample.time,
+            None => return Vec::new(),
+        };
+        let mut events = Vec::new();
+        for (rule, debounced) in &mut self.rules {
+            let value = match rule.stage.as_str() {
+                "output" => state.last_pushed,
+                "tachometer" => state.last_rpm,
+                stage => self.values.get(&format!("{}{}", self.prefix, stage)),
+            };
+            let holds = match rule.when {
+                Condition::Above(threshold) => value.is_some_and(|value| value > threshold),
+                Condition::Below(threshold) => value.is_some_and(|value| value < threshold),
+                Condition::Error if rule.stage == "input" => state.input_errors > 0,
+                Condition::Error => value.is_none(),
+            };
+            if let Some(active) = debounced.update(time, holds) {
+                let mut values = BTreeMap::new();
+                if let Some(value) = value {
+                    values.insert("value".to_string(), value);
+                }
+                let hook = if active { &rule.hook } else { &rule.resolved };
+                events.push(AlarmEvent {
+                    alarm: rule.name.clone(),
+                    active,
+                    time,
+                    values,
+                    hook: hook.clone(),
+                });
+            }
+        }
+        events
+    }
+}
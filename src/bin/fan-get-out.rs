@@ -8,8 +8,8 @@ fn main() {
         .version("0.1")
         .author("")
         .about(
-            "Command line client to retrieve current output of the fand control loop. ONLY WORKS
-            WITH DEFAULT CONFIG",
+            "Command line client to retrieve current output of an operation of the fand control
+            loop (by default the last one of the default config)",
         )
         .arg(
             Arg::with_name("SOCKET")
@@ -17,11 +17,29 @@ fn main() {
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("OPERATION")
+                .help(
+                    "Index of the operation, prefixed by the name of its pipeline (e.g. cpu/7) \
+                     when fand runs several of them.",
+                )
+                .default_value("7")
+                .index(2),
+        )
         .get_matches();
 
     let socket_path = matches
         .value_of("SOCKET")
         .expect("Must provide a valid path to the socket used by fand");
+    let operation = matches.value_of("OPERATION").unwrap();
+    let (pipeline, index) = match operation.rfind('/') {
+        Some(slash) => (&operation[..=slash], &operation[slash + 1..]),
+        None => ("", operation),
+    };
+    let index = index
+        .parse::<usize>()
+        .expect("The index of the operation must be a number");
+    let operation_id = format!("{}{}", pipeline, index);
 
     let stream =
         BufReader::new(UnixStream::connect(socket_path).expect("Failed to connect to socket"));
@@ -29,9 +47,9 @@ fn main() {
     for line in stream.lines() {
         let line = line.unwrap();
         let mut parts = line.split(":");
-        let id = parts.next().unwrap().parse::<usize>().unwrap();
+        let id = parts.next().unwrap().trim();
         let operation_name = parts.next().unwrap().trim();
-        if id == 7 && operation_name == ">" {
+        if id == operation_id && operation_name == ">" {
             let the_rest = parts.next().unwrap().parse::<f64>().unwrap();
             println!("{:2.0}", the_rest);
             break;
@@ -0,0 +1,502 @@
+use clap::{App, Arg, ArgMatches};
+use pifan::clock::{SharedClock, SystemClock, VirtualClock};
+use pifan::config::{self, Config, Format};
+use pifan::operations::parameters::{values_taken, RelayParameters};
+use pifan::pipeline::Pipeline;
+use pifan::sample::Sample;
+use pifan::simulation;
+use pifan::tuning::{self, Gains, ResponseMetrics, Stimulus, TuningRule};
+use std::sync::Arc;
+
+/// Print the errors and exit.
+fn exit(errors: &[String]) -> ! {
+    for err in errors {
+        eprintln!("{}", err);
+    }
+    std::process::exit(1);
+}
+
+/// Parse a list of `count` numbers separated by colons (e.g. `40:50`).
+fn parse_numbers(text: &str, count: usize, what: &str) -> Vec<f64> {
+    let numbers: Vec<f64> = text
+        .split(':')
+        .map(|number| number.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|err| exit(&[format!("Invalid {} `{}`: {}", what, text, err)]));
+    if numbers.len() != count {
+        exit(&[format!(
+            "Invalid {} `{}`: expected {} numbers separated by colons",
+            what, text, count
+        )]);
+    }
+    numbers
+}
+
+fn parse_number(matches: &ArgMatches, name: &str) -> Option<f64> {
+    matches
+        .value_of(name)
+        .map(|text| parse_numbers(text, 1, name)[0])
+}
+
+/// The pipeline of the config file to tune: the only one, or the one given with `--pipeline`.
+fn select_pipeline(config: Config, name: Option<&str>) -> Pipeline {
+    let mut pipelines = config.into_pipelines();
+    match name {
+        Some(name) => pipelines
+            .into_iter()
+            .find(|(other, _)| other.as_deref() == Some(name))
+            .map(|(_, pipeline)| pipeline)
+            .unwrap_or_else(|| exit(&[format!("No pipeline named {}", name)])),
+        None if pipelines.len() == 1 => pipelines.remove(0).1,
+        None => {
+            let names: Vec<String> = pipelines.into_iter().filter_map(|(name, _)| name).collect();
+            exit(&[format!(
+                "Several pipelines in the config file; choose one with --pipeline ({})",
+                names.join(", ")
+            )])
+        }
+    }
+}
+
+fn describe(stimulus: &Option<Stimulus>) -> String {
+    match stimulus {
+        Some(Stimulus::Step { from, to, at }) => {
+            format!("a step of the input from {} to {} at {} s", from, to, at)
+        }
+        Some(Stimulus::Ramp {
+            from,
+            to,
+            start,
+            end,
+        }) => format!(
+            "a ramp of the input from {} to {} between {} s and {} s",
+            from, to, start, end
+        ),
+        None => "its own input".to_string(),
+    }
+}
+
+fn report(metrics: &ResponseMetrics, band: f64) {
+    let seconds = |time: Option<f64>, otherwise: &str| {
+        time.map_or_else(|| otherwise.to_string(), |time| format!("{:.3} s", time))
+    };
+    println!("  initial value        {:.3}", metrics.initial);
+    println!("  target               {:.3}", metrics.target);
+    println!("  last value           {:.3}", metrics.last);
+    println!(
+        "  rise time (10-90%)   {}",
+        seconds(metrics.rise_time, "never")
+    );
+    println!("  overshoot            {:.1} %", metrics.overshoot);
+    println!(
+        "  {:<21}{}",
+        format!("settling time ({}%)", band),
+        seconds(metrics.settling_time, "not settled")
+    );
+    println!("  steady-state error   {:.3}", metrics.steady_state_error);
+    println!("  output changes       {}", metrics.output_changes);
+}
+
+/// A relay experiment replacing the PID control at operation `index` of a pipeline.
+struct RelayExperiment {
+    index: usize,
+    kind: String,
+    setpoint: f64,
+    relay: RelayParameters,
+    /// Unit of time of the PID control, in seconds
+    time_unit: f64,
+}
+
+/// Replace the PID control of the pipeline (operation `stage`, or the first `pid` or `signed_pid`
+/// operation) by a relay switching between `low` and `high` around its setpoint.
+fn relay_experiment(
+    pipeline: &mut Pipeline,
+    stage: Option<usize>,
+    low: f64,
+    high: f64,
+    hysteresis: f64,
+) -> RelayExperiment {
+    let operations: Vec<serde_json::Value> = pipeline
+        .operations
+        .iter()
+        .map(|operation| serde_json::to_value(operation).unwrap_or_default())
+        .collect();
+    let is_pid = |operation: &serde_json::Value| {
+        matches!(operation["type"].as_str(), Some("pid") | Some("signed_pid"))
+    };
+    let index = match stage {
+        Some(index) if index < operations.len() && is_pid(&operations[index]) => index,
+        Some(index) => exit(&[format!("Operation {} is not a PID control", index)]),
+        None => operations
+            .iter()
+            .position(is_pid)
+            .unwrap_or_else(|| exit(&["The pipeline has no PID control".to_string()])),
+    };
+    let operation = &operations[index];
+    let kind = operation["type"].as_str().unwrap_or_default().to_string();
+    let setpoint = operation["setpoint"].as_f64().unwrap_or_default();
+    let direction = serde_json::from_value(operation["direction"].clone()).unwrap_or_default();
+    // the operations after the control (e.g. a supersample) change how often it runs: it
+    // produces `calls / ticks` values per sample of the pipeline
+    let (calls, ticks) = values_taken(&pipeline.operations[index + 1..]);
+    let interval = pipeline.sample_rate as f64 / 1000.0 * ticks as f64 / calls as f64;
+    // `pid` integrates once per value, `signed_pid` in seconds unless it has a fixed time step
+    let time_unit = match (kind.as_str(), operation["dt"].as_f64()) {
+        ("pid", _) => interval,
+        (_, Some(dt)) => interval / dt,
+        _ => 1.0,
+    };
+    let relay = RelayParameters {
+        setpoint,
+        low,
+        high,
+        hysteresis,
+        direction,
+    };
+    pipeline.operations[index] = Box::new(relay);
+    RelayExperiment {
+        index,
+        kind,
+        setpoint,
+        relay,
+        time_unit,
+    }
+}
+
+/// Number of oscillations left out at the start of a relay experiment.
+const SKIPPED_CYCLES: usize = 2;
+
+/// Report the ultimate point measured by a relay experiment and the gains suggested by every
+/// [TuningRule] (in the units of the PID control), which are returned.
+fn autotune(experiment: &RelayExperiment, input: &[Sample], duration: f64) -> Vec<Gains> {
+    let relay = &experiment.relay;
+    let point = tuning::relay_ultimate_point(
+        input,
+        experiment.setpoint,
+        (relay.high - relay.low).abs() / 2.0,
+        relay.hysteresis,
+        SKIPPED_CYCLES,
+    )
+    .unwrap_or_else(|| {
+        exit(&["No sustained oscillation of the input around the setpoint; run for longer (--duration) or change the levels of the relay".to_string()])
+    });
+    println!(
+        "Relay experiment replacing operation {} ({}), between {} and {} around {}, over {} s:",
+        experiment.index, experiment.kind, relay.low, relay.high, experiment.setpoint, duration
+    );
+    println!("  oscillations         {}", point.cycles);
+    println!("  amplitude            {:.3}", point.amplitude);
+    println!("  ultimate gain (Ku)   {:.4}", point.gain);
+    println!("  ultimate period (Tu) {:.3} s", point.period);
+    println!("Suggested gains:");
+    TuningRule::ALL
+        .iter()
+        .map(|rule| {
+            let gains = rule.gains(&point).in_time_unit(experiment.time_unit);
+            println!(
+                "  {:<20} kp = {:.4}, ki = {:.4}, kd = {:.4}",
+                rule.name(),
+                gains.kp,
+                gains.ki,
+                gains.kd
+            );
+            gains
+        })
+        .collect()
+}
+
+fn main() {
+    let matches = App::new("Tuning of fand pipelines")
+        .version("0.1")
+        .author("")
+        .about("Applies a pipeline to a synthetic step or ramp input (or to its own input, e.g. a simulated plant) on a virtual clock, and reports the metrics of its response; or suggests PID gains from a relay experiment")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Config file of the pipeline")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Format of the config file (json, toml or yaml); guessed from its extension by default")
+                .possible_values(&["json", "toml", "yaml"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pipeline")
+                .short("p")
+                .long("pipeline")
+                .value_name("NAME")
+                .help("Pipeline to tune, if the config file has several")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("step")
+                .long("step")
+                .value_name("FROM:TO")
+                .help("Replaces the input by a step from FROM to TO (at the time given with --at)")
+                .conflicts_with("ramp")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ramp")
+                .long("ramp")
+                .value_name("FROM:TO:SECONDS")
+                .help("Replaces the input by a ramp from FROM to TO lasting SECONDS (from the time given with --at)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("at")
+                .long("at")
+                .value_name("SECONDS")
+                .help("Time at which the step or ramp starts (defaults to 10)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .short("d")
+                .long("duration")
+                .value_name("SECONDS")
+                .help("Simulated time to run for (defaults to 600)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("response")
+                .long("response")
+                .value_name("STAGE")
+                .help("Signal whose response is measured: the output of the pipeline (the default with --step or --ramp) or its input (the default otherwise, e.g. the temperature of a simulated plant)")
+                .possible_values(&["input", "output"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .value_name("VALUE")
+                .help("Value the response should reach, e.g. the setpoint (defaults to its last value)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("band")
+                .long("band")
+                .value_name("PERCENT")
+                .help("Band around the target within which the response is settled, in % of the change (defaults to 2)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("plot")
+                .long("plot")
+                .value_name("KIND")
+                .help("Also plots the response, as text or as an SVG image")
+                .possible_values(&["ascii", "svg"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("plot-output")
+                .long("plot-output")
+                .value_name("FILE")
+                .help("Writes the plot to FILE instead of the standard output")
+                .requires("plot")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("autotune")
+                .long("autotune")
+                .value_name("LOW:HIGH")
+                .help("Runs a relay experiment instead: the PID control is replaced by a relay switching between LOW and HIGH around its setpoint, and PID gains are suggested from the resulting oscillation of the input")
+                .conflicts_with_all(&["step", "ramp", "response"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stage")
+                .long("stage")
+                .value_name("INDEX")
+                .help("Index of the PID control replaced by the relay (defaults to the first one)")
+                .requires("autotune")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hysteresis")
+                .long("hysteresis")
+                .value_name("VALUE")
+                .help("Hysteresis of the relay (defaults to 0)")
+                .requires("autotune")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("write")
+                .long("write")
+                .value_name("RULE")
+                .help("Writes the gains suggested by RULE back into the config file (the comments of a JSON or YAML file are lost)")
+                .possible_values(&["ziegler-nichols", "tyreus-luyben"])
+                .requires("autotune")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("realtime")
+                .long("realtime")
+                .help("Runs in real time, driving the actual output of the pipeline (e.g. to autotune on the real hardware), instead of as fast as possible with outputs other than simulated fans discarded"),
+        )
+        .get_matches();
+
+    let filename = matches.value_of("config").unwrap();
+    let format = matches
+        .value_of("format")
+        .map(|format| format.parse::<Format>().unwrap());
+    let config = Config::from_file(filename, format)
+        .unwrap_or_else(|err| exit(&[format!("{}: {}", filename, err)]));
+    let pipeline_name = matches.value_of("pipeline");
+    let mut pipeline = select_pipeline(config, pipeline_name);
+
+    let at = parse_number(&matches, "at").unwrap_or(10.0);
+    let stimulus = if let Some(step) = matches.value_of("step") {
+        let numbers = parse_numbers(step, 2, "step");
+        Some(Stimulus::Step {
+            from: numbers[0],
+            to: numbers[1],
+            at,
+        })
+    } else if let Some(ramp) = matches.value_of("ramp") {
+        let numbers = parse_numbers(ramp, 3, "ramp");
+        Some(Stimulus::Ramp {
+            from: numbers[0],
+            to: numbers[1],
+            start: at,
+            end: at + numbers[2],
+        })
+    } else {
+        None
+    };
+    let duration = parse_number(&matches, "duration").unwrap_or(600.0);
+    let band = parse_number(&matches, "band").unwrap_or(2.0);
+    let target = parse_number(&matches, "target");
+    let measure_input = match matches.value_of("response") {
+        Some(response) => response == "input",
+        None => stimulus.is_none(),
+    };
+    let experiment = matches.value_of("autotune").map(|levels| {
+        let levels = parse_numbers(levels, 2, "autotune");
+        let stage = matches.value_of("stage").map(|stage| {
+            stage
+                .parse::<usize>()
+                .unwrap_or_else(|err| exit(&[format!("Invalid stage `{}`: {}", stage, err)]))
+        });
+        let hysteresis = parse_number(&matches, "hysteresis").unwrap_or(0.0);
+        relay_experiment(&mut pipeline, stage, levels[0], levels[1], hysteresis)
+    });
+
+    let realtime = matches.is_present("realtime");
+    if !realtime {
+        pipeline.output = pipeline.output.simulated();
+    }
+    if stimulus.is_some() {
+        if pipeline.input.is_none() {
+            exit(&[
+                "The input of a graph of nodes cannot be replaced by a step or ramp".to_string(),
+            ]);
+        }
+        pipeline.input = None;
+    }
+    let errors = pipeline.check_devices();
+    if !errors.is_empty() {
+        exit(
+            &errors
+                .iter()
+                .map(|err| format!("{}: {}", filename, err))
+                .collect::<Vec<_>>(),
+        );
+    }
+    let clock: SharedClock = if realtime {
+        SystemClock::shared()
+    } else {
+        Arc::new(VirtualClock::new())
+    };
+    let mut builder = pipeline.into_builder_with_clock(clock.clone());
+    if let Some(stimulus) = stimulus {
+        builder = builder.input(stimulus.input(clock.clone()));
+    }
+    let mut control = [builder.build().expect("Failed to build pipeline")];
+
+    let mut response: Vec<Sample> = Vec::new();
+    let mut pushed = Vec::new();
+    simulation::run_for(
+        &mut control,
+        clock.as_ref(),
+        duration,
+        |_, control, sample| {
+            let state = control.state();
+            response.push(if measure_input {
+                state.last_input.unwrap_or(sample)
+            } else {
+                sample
+            });
+            pushed.push(state.last_pushed);
+        },
+    );
+
+    let signal = if measure_input { "input" } else { "output" };
+    let plot_target = match &experiment {
+        Some(experiment) => {
+            let gains = autotune(experiment, &response, duration);
+            if let Some(rule) = matches.value_of("write") {
+                let index = TuningRule::ALL
+                    .iter()
+                    .position(|other| other.name().eq_ignore_ascii_case(rule))
+                    .unwrap();
+                let (rule, gains) = (TuningRule::ALL[index], gains[index]);
+                let fields = [("kp", gains.kp), ("ki", gains.ki), ("kd", gains.kd)];
+                config::set_operation_fields(
+                    filename,
+                    format,
+                    pipeline_name,
+                    experiment.index,
+                    &fields,
+                )
+                .unwrap_or_else(|err| exit(&[format!("{}: {}", filename, err)]));
+                println!(
+                    "\nWrote the {} gains to operation {} of {}",
+                    rule.name(),
+                    experiment.index,
+                    filename
+                );
+            }
+            experiment.setpoint
+        }
+        None => {
+            let start = stimulus.map_or(0.0, |stimulus| stimulus.start());
+            let metrics =
+                tuning::analyze(&response, &pushed, start, target, band).unwrap_or_else(|| {
+                    exit(&["The pipeline produced no values after the start".to_string()])
+                });
+            println!(
+                "Response of the {} of the pipeline to {}, over {} s:",
+                signal,
+                describe(&stimulus),
+                duration
+            );
+            report(&metrics, band);
+            metrics.target
+        }
+    };
+
+    if let Some(kind) = matches.value_of("plot") {
+        let plot = match kind {
+            "svg" => tuning::svg_plot(
+                &response,
+                Some(plot_target),
+                &format!("Response of the {} ({})", signal, filename),
+            ),
+            _ => tuning::ascii_plot(&response, Some(plot_target), 72, 20),
+        };
+        match matches.value_of("plot-output") {
+            Some(path) => std::fs::write(path, plot)
+                .unwrap_or_else(|err| exit(&[format!("Failed to write {}: {}", path, err)])),
+            None => print!("\n{}", plot),
+        }
+    }
+}
@@ -1,24 +1,287 @@
-use clap::{App, Arg};
-use log::{debug, trace};
-use pid::Pid;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{debug, error, info, trace};
+use pifan::clock::{SystemClock, VirtualClock};
+use pifan::config::{Config, ConfigError, Format};
 use pifan::inputs::Input;
 use pifan::operations::parameters::*;
 use pifan::outputs::Output;
-use pifan::pipeline::Pipeline;
-use simplelog::*;
-use std::fs::File;
+use pifan::pipeline::{Pipeline, PipelineHandle, StopReason};
+use pifan::recording::Recorder;
+use pifan::simulation::{self, Fans};
+use pifan::state::{config_hash, StateStore};
+use pifan::systemd::ServiceNotifier;
+use pifan::validation::ValidationError;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use simplelog::{LevelFilter, TermLogger, TerminalMode};
 use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use std::vec;
 
-fn bind_socket_and_listen(socket_path: &str, pipeline: Pipeline) {
+/// Time between two checks for signals (and for the end of the control loops).
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Prefix errors found in a config file by the name of the file.
+fn located(filename: &str, errors: &[ValidationError]) -> Vec<String> {
+    errors
+        .iter()
+        .map(|err| format!("{}: {}", filename, err))
+        .collect()
+}
+
+/// Read a config file (in the format given with `--format`, if any) and check it; all the
+/// problems found are returned, prefixed by the name of the file.
+fn read_config(filename: &str, matches: &ArgMatches) -> Result<Config, Vec<String>> {
+    let format = matches
+        .value_of("format")
+        .map(|format| format.parse::<Format>().unwrap());
+    match Config::from_file(filename, format) {
+        Ok(config) => Ok(config),
+        Err(ConfigError::Invalid(errors)) => Err(located(filename, &errors)),
+        Err(err) => Err(vec![format!("{}: {}", filename, err)]),
+    }
+}
+
+/// Same as [read_config], but also check the availability of the devices the config uses.
+fn load_config(filename: &str, matches: &ArgMatches) -> Result<Config, Vec<String>> {
+    let config = read_config(filename, matches)?;
+    let errors = config.check_devices();
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(located(filename, &errors))
+    }
+}
+
+/// Run the pipelines of a config file on a virtual clock for `duration` seconds (of simulated
+/// time), as fast as possible, and write a trace of every sample as CSV to `trace`. Outputs other
+/// than simulated fans are discarded (see [Output::simulated]).
+fn simulate(filename: &str, matches: &ArgMatches, duration: f64, trace: Box<dyn Write>) {
+    let exit = |errors: Vec<String>| -> ! {
+        for err in errors {
+            eprintln!("{}", err);
+        }
+        std::process::exit(1);
+    };
+    let config = read_config(filename, matches).unwrap_or_else(|errors| exit(errors));
+    let recorder = matches.value_of("record").map(|path| {
+        let recorder = Recorder::create(path).unwrap_or_else(|err| {
+            exit(vec![format!(
+                "Failed to create recording {}: {}",
+                path, err
+            )])
+        });
+        Arc::new(recorder)
+    });
+    let clock = Arc::new(VirtualClock::new());
+    // the pipelines may act on each other's simulated plants
+    let fans = Fans::default();
+    let mut names = Vec::new();
+    let mut loops = Vec::new();
+    for (name, mut pipeline) in config.into_pipelines() {
+        pipeline.output = pipeline.output.simulated();
+        let errors = pipeline.check_devices();
+        if !errors.is_empty() {
+            exit(located(filename, &errors));
+        }
+        let mut builder = pipeline.into_builder_with(clock.clone(), &fans);
+        if let Some(name) = &name {
+            builder = builder.name(name.clone());
+        }
+        if let Some(recorder) = &recorder {
+            builder = builder.record_with(Arc::clone(recorder));
+        }
+        let control = builder.build().expect("Failed to build pipeline");
+        names.push(name.unwrap_or_default());
+        loops.push(control);
+    }
+
+    let mut writer = csv::Writer::from_writer(trace);
+    let mut result = writer.write_record(["time", "pipeline", "input", "output", "pushed"]);
+    let format = |value: Option<f64>| value.map_or_else(String::new, |value| value.to_string());
+    simulation::run_for(
+        &mut loops,
+        clock.as_ref(),
+        duration,
+        |index, control, sample| {
+            if result.is_ok() {
+                let state = control.state();
+                result = writer.write_record([
+                    sample.time.to_string(),
+                    names[index].clone(),
+                    format(state.last_input.map(|input| input.value)),
+                    sample.value.to_string(),
+                    format(state.last_pushed),
+                ]);
+            }
+        },
+    );
+    if let Err(err) = result.and_then(|_| writer.flush().map_err(csv::Error::from)) {
+        exit(vec![format!("Failed to write trace: {}", err)]);
+    }
+}
+
+/// Save the state of the operations to `path`, logging any failure.
+fn save_state(store: &StateStore, path: &str) {
+    if let Err(err) = store.save(path) {
+        error!("Failed to save state to {}: {}", path, err);
+    }
+}
+
+/// Save the state of the operations to `path` every `interval` (it is saved once more when fand is
+/// terminated or its control loops finished, see [main]).
+fn persist_state(store: Arc<StateStore>, path: String, interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        save_state(&store, &path);
+    });
+}
+
+/// Start the control loop of every pipeline on its own thread; if a monitoring channel is given,
+/// all pipelines report their internal state through it, if a state store is given they keep the
+/// state of their operations in it, and if a recorder is given they record all their stages with
+/// it. Their simulated plants, outputs and tachometers share the same fans.
+fn spawn_pipelines(
+    pipelines: Vec<(Option<String>, Pipeline)>,
+    tx: Option<mpsc::Sender<String>>,
+    store: Option<Arc<StateStore>>,
+    recorder: Option<Arc<Recorder>>,
+) -> Vec<(Option<String>, PipelineHandle)> {
+    let fans = Fans::default();
+    pipelines
+        .into_iter()
+        .map(|(name, pipeline)| {
+            let mut builder = pipeline.into_builder_with(SystemClock::shared(), &fans);
+            if let Some(name) = &name {
+                debug!("Starting pipeline {}", name);
+                builder = builder.name(name.clone());
+            }
+            if let Some(tx) = &tx {
+                builder = builder.monitor_with(tx.clone());
+            }
+            if let Some(store) = &store {
+                builder = builder.persist_with(Arc::clone(store));
+            }
+            if let Some(recorder) = &recorder {
+                builder = builder.record_with(Arc::clone(recorder));
+            }
+            let handle = builder.build().expect("Failed to build pipeline").spawn();
+            (name, handle)
+        })
+        .collect()
+}
+
+/// The running control loops, together with what they share, so that they can be replaced by the
+/// loops of a new configuration.
+struct Daemon {
+    handles: Vec<(Option<String>, PipelineHandle)>,
+    tx: Option<mpsc::Sender<String>>,
+    store: Arc<StateStore>,
+    recorder: Option<Arc<Recorder>>,
+    notifier: Option<Arc<Mutex<ServiceNotifier>>>,
+}
+
+impl Daemon {
+    /// Start the control loops of `config`.
+    fn start(&mut self, config: Config) {
+        self.handles = spawn_pipelines(
+            config.into_pipelines(),
+            self.tx.clone(),
+            Some(Arc::clone(&self.store)),
+            self.recorder.clone(),
+        );
+        if let Some(notifier) = &self.notifier {
+            let mut notifier = notifier.lock().unwrap();
+            for (name, handle) in &self.handles {
+                notifier.watch(name.as_deref(), handle);
+            }
+        }
+    }
+
+    /// Replace the control loops by those of the config file `filename`; the operations of the new
+    /// loops start from the state of the operations of the same type at the same place in the
+    /// previous ones. If the new configuration has problems, they are logged and the current loops
+    /// keep running.
+    fn reload(&mut self, filename: &str, matches: &ArgMatches) {
+        info!("Reloading configuration from {}", filename);
+        let config = match load_config(filename, matches) {
+            Ok(config) => config,
+            Err(errors) => {
+                for err in errors {
+                    error!("{}", err);
+                }
+                error!("Keeping the current configuration");
+                return;
+            }
+        };
+        if let Some(notifier) = &self.notifier {
+            notifier.lock().unwrap().reloading();
+        }
+        // the outputs of the current loops are released before the new loops open theirs
+        for (_, handle) in &self.handles {
+            handle.stop();
+        }
+        for (name, handle) in self.handles.drain(..) {
+            if handle.join().is_err() {
+                error!(
+                    "Control loop {} panicked",
+                    name.as_deref().unwrap_or("(unnamed)")
+                );
+            }
+        }
+        self.store.set_config_hash(config_hash(&config));
+        self.start(config);
+        self.store.prune();
+    }
+
+    /// Whether all the control loops finished (e.g. their inputs ended).
+    fn finished(&self) -> bool {
+        self.handles.iter().all(|(_, handle)| handle.is_finished())
+    }
+
+    /// Wait for the control loops to finish, log why they did, and save the state to `state_path`
+    /// if given; returns the exit code of fand, 1 if a loop panicked or its input failed.
+    fn finish(self, state_path: Option<&str>) -> i32 {
+        let mut code = 0;
+        for (name, handle) in self.handles {
+            let name = name.as_deref().unwrap_or("(unnamed)");
+            let stopped = handle.state().stopped;
+            if handle.join().is_err() {
+                error!("Control loop {} panicked", name);
+                code = 1;
+                continue;
+            }
+            match stopped {
+                Some(StopReason::InputFailed) => {
+                    error!("Control loop {} stopped: its input could not be read", name);
+                    code = 1;
+                }
+                Some(StopReason::InputEnded) => {
+                    info!("Control loop {} finished: its input ended", name)
+                }
+                Some(StopReason::Stopped) | None => info!("Control loop {} was stopped", name),
+            }
+        }
+        if let Some(path) = state_path {
+            debug!("Saving state to {}", path);
+            save_state(&self.store, path);
+        }
+        code
+    }
+}
+
+/// Serve the monitoring messages received through `rx` to the clients of a unix socket, on their
+/// own threads; fand exits if the socket stops accepting clients.
+fn bind_socket_and_listen(socket_path: &str, rx: mpsc::Receiver<String>) {
     let listener = {
         debug!("Starting UNIX socket at: {}", socket_path);
         let listener = UnixListener::bind(socket_path)
-            .expect(format!("Failed to open socket at {}", socket_path).as_str());
+            .unwrap_or_else(|_| panic!("Failed to open socket at {}", socket_path));
         // TODO: Hack to make it easy to use the socket; setting such permissions doesn't feel
         // very UNIX-y
         std::fs::metadata(socket_path)
@@ -34,10 +297,6 @@ fn bind_socket_and_listen(socket_path: &str, pipeline: Pipeline) {
 
     let clients: Arc<Mutex<Vec<UnixStream>>> = Arc::new(Mutex::new(Vec::new()));
 
-    // The pipeline spawns a thread to do the control loop and gives back a channel to get internal
-    // state information.
-    let rx = pipeline.start(true).unwrap();
-
     let clients_copy = Arc::clone(&clients);
 
     // Start a thread to send data to any active clients
@@ -67,37 +326,54 @@ fn bind_socket_and_listen(socket_path: &str, pipeline: Pipeline) {
         }
     });
 
-    // In main thread listen for incoming connections into the socket
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let mut current_clients = clients.lock().unwrap();
-                current_clients.push(stream);
-            }
-            Err(err) => {
-                debug!("Error while handling incoming connection: {}", err);
-                // Here something very wrong must have happened... if we continue down this path
-                // the application should on main since it is not expecting to ever return from
-                // this method.
-                break;
+    // Listen for incoming connections into the socket on another thread
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let mut current_clients = clients.lock().unwrap();
+                    current_clients.push(stream);
+                }
+                Err(err) => {
+                    debug!("Error while handling incoming connection: {}", err);
+                    // Here something very wrong must have happened... the application should
+                    // stop since it is not expecting the socket to ever stop.
+                    break;
+                }
             }
         }
-    }
+        error!("Stopped accepting clients of the socket");
+        std::process::exit(1);
+    });
 }
 
 fn main() {
+    let config_arg = Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .value_name("FILE")
+        .help("Sets a custom config file")
+        .takes_value(true);
+    let format_arg = Arg::with_name("format")
+        .short("f")
+        .long("format")
+        .value_name("FORMAT")
+        .help(
+            "Format of the config file (json, toml or yaml); guessed from its extension by default",
+        )
+        .possible_values(&["json", "toml", "yaml"])
+        .takes_value(true);
+    let record_arg = Arg::with_name("record")
+        .long("record")
+        .value_name("FILE")
+        .help("Records the values of every stage of the control loops to FILE (.csv or .jsonl), e.g. to replay them later")
+        .takes_value(true);
     let matches = App::new("Fan speed control")
         .version("0.1")
         .author("")
         .about("Configurable control system")
-        .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("config")
-                .value_name("FILE")
-                .help("Sets a custom config file")
-                .takes_value(true),
-        )
+        .arg(config_arg.clone())
+        .arg(format_arg.clone())
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -114,79 +390,264 @@ fn main() {
                 )
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .value_name("FILE")
+                .help("Saves the state of the control loops to FILE (e.g. /var/lib/fand/state.json) periodically and on shutdown, and restores it at start if the configuration did not change")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("state-interval")
+                .long("state-interval")
+                .value_name("SECONDS")
+                .help("Time between saves of the state (defaults to 60)")
+                .requires("state")
+                .takes_value(true),
+        )
+        .arg(record_arg.clone())
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks a config file and reports all the problems found in it")
+                .arg(config_arg.clone().required(true))
+                .arg(format_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate")
+                .about("Runs a config file as fast as possible on a virtual clock (against simulated or recorded inputs) and writes a trace of it as CSV")
+                .arg(config_arg.required(true))
+                .arg(format_arg)
+                .arg(record_arg)
+                .arg(
+                    Arg::with_name("duration")
+                        .short("d")
+                        .long("duration")
+                        .value_name("SECONDS")
+                        .help("Simulated time to run for")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Writes the trace to FILE instead of the standard output")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("schema")
+                .about("Prints the JSON schema of config files (e.g. for completion in editors)"),
+        )
         .get_matches();
 
     match matches.occurrences_of("v") {
-        0 => TermLogger::init(LevelFilter::Error, Config::default(), TerminalMode::Mixed).unwrap(),
-        1 => TermLogger::init(LevelFilter::Debug, Config::default(), TerminalMode::Mixed).unwrap(),
-        2 => TermLogger::init(LevelFilter::Trace, Config::default(), TerminalMode::Mixed).unwrap(),
-        3 | _ => println!("Don't be crazy"),
+        0 => TermLogger::init(
+            LevelFilter::Error,
+            simplelog::Config::default(),
+            TerminalMode::Mixed,
+        )
+        .unwrap(),
+        1 => TermLogger::init(
+            LevelFilter::Debug,
+            simplelog::Config::default(),
+            TerminalMode::Mixed,
+        )
+        .unwrap(),
+        2 => TermLogger::init(
+            LevelFilter::Trace,
+            simplelog::Config::default(),
+            TerminalMode::Mixed,
+        )
+        .unwrap(),
+        _ => println!("Don't be crazy"),
     }
 
     debug!("Starting with debug information enabled.");
     trace!("Tracing information enabled.");
 
-    let pipeline: Pipeline = match matches.value_of("config") {
+    if matches.subcommand_matches("schema").is_some() {
+        let schema = schemars::schema_for!(Config);
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("check") {
+        let filename = matches.value_of("config").unwrap();
+        match load_config(filename, matches) {
+            Ok(_) => println!("{}: OK", filename),
+            Err(errors) => {
+                for err in errors {
+                    eprintln!("{}", err);
+                }
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("simulate") {
+        let filename = matches.value_of("config").unwrap();
+        let duration = matches
+            .value_of("duration")
+            .unwrap()
+            .parse::<f64>()
+            .expect("Invalid duration");
+        let trace: Box<dyn Write> = match matches.value_of("output") {
+            Some(path) => Box::new(std::fs::File::create(path).unwrap_or_else(|err| {
+                eprintln!("Failed to create {}: {}", path, err);
+                std::process::exit(1);
+            })),
+            None => Box::new(std::io::stdout()),
+        };
+        simulate(filename, matches, duration, trace);
+        return;
+    }
+
+    let config: Config = match matches.value_of("config") {
         Some(filename) => {
             debug!("Reading configuration from: {}", filename);
-            let config_file = File::open(filename).expect("Failed to read config file");
-            serde_json::from_reader(config_file).expect("Failed to parse config file")
+            load_config(filename, &matches).unwrap_or_else(|errors| {
+                for err in errors {
+                    error!("{}", err);
+                }
+                std::process::exit(1);
+            })
         }
         None => {
             debug!("Using default configuration (use verbose level 2 to print it out)");
             let default_pipeline = Pipeline {
-                input: Input::RPiCpuTemp,
+                input: Some(Input::RPiCpuTemp),
+                nodes: Default::default(),
+                from: None,
                 operations: vec![
-                    OperationParameters::Average(AverageParameters { n: 5 }),
-                    OperationParameters::PID(PIDParameters {
-                        pid: Pid::new(2., 2.0, 5., 100., 10., 30., 35.),
+                    Box::new(AverageParameters { n: 5 }),
+                    Box::new(PIDParameters {
+                        kp: 2.0,
+                        ki: 2.0,
+                        kd: 5.0,
+                        p_limit: 100.0,
+                        i_limit: 10.0,
+                        d_limit: 30.0,
+                        setpoint: 35.0,
                         offset: 30,
                     }),
-                    OperationParameters::Clip(ClipParameters {
+                    Box::new(ClipParameters {
                         min: 30.0,
                         max: 100.0,
                     }),
-                    OperationParameters::Supersample(SupersampleParameters { n: 100 }),
-                    OperationParameters::DampenedOscillator(DampenedOscillatorParameters {
+                    Box::new(SupersampleParameters {
+                        n: 100,
+                        period: None,
+                    }),
+                    Box::new(DampenedOscillatorParameters {
                         m: 0.5,
                         k: 2.,
-                        dt: 0.25,
-                        target: 0.0,
+                        dt: None,
                     }),
-                    OperationParameters::DampenedOscillator(DampenedOscillatorParameters {
+                    Box::new(DampenedOscillatorParameters {
                         m: 1.0,
                         k: 1.,
-                        dt: 0.25,
-                        target: 0.0,
+                        dt: None,
                     }),
-                    OperationParameters::Clip(ClipParameters {
+                    Box::new(ClipParameters {
                         min: 30.0,
                         max: 100.0,
                     }),
-                    OperationParameters::Subsample(SubsampleParameters { n: 4 }),
+                    Box::new(SubsampleParameters { n: 4 }),
                 ],
                 output: Output::PWM,
                 sample_rate: 1000,
+                tachometer: None,
+                stall: None,
+                over_temperature: None,
+                on_input_error: None,
+                alerts: Vec::new(),
             };
             trace!(
                 "{}",
                 serde_json::to_string_pretty(&default_pipeline).unwrap()
             );
-            default_pipeline
+            Config::Single(Box::new(default_pipeline))
         }
     };
+    // the state of the operations is always kept, to be carried over when the configuration is
+    // reloaded, but only saved with --state
+    let state_path = matches.value_of("state");
+    let store = Arc::new(match state_path {
+        Some(path) => StateStore::load(path, config_hash(&config)),
+        None => StateStore::new(config_hash(&config)),
+    });
+    if let Some(path) = state_path {
+        let interval = matches.value_of("state-interval").map_or(60.0, |interval| {
+            interval.parse::<f64>().expect("Invalid state interval")
+        });
+        persist_state(
+            Arc::clone(&store),
+            path.to_string(),
+            Duration::from_secs_f64(interval),
+        );
+    }
+    let recorder = matches.value_of("record").map(|path| {
+        let recorder = Recorder::create(path).unwrap_or_else(|err| {
+            error!("Failed to create recording {}: {}", path, err);
+            std::process::exit(1);
+        });
+        Arc::new(recorder)
+    });
 
-    // If a UNIX socket is requested we need to fork to serve clients and to perform the control
-    // loop, otherwise we just execute the control loop in the main thread.
-
-    match matches.value_of("socket") {
-        Some(socket_path) => bind_socket_and_listen(socket_path, pipeline),
-        None => {
-            pipeline.start(false);
-        } // in current implementation this is blocking and will never return
+    // The control loops run on their own threads and, if a UNIX socket is requested, share a
+    // channel to give back internal state information.
+    let (tx, rx) = match matches.value_of("socket") {
+        Some(_) => {
+            let (tx, rx) = mpsc::channel();
+            (Some(tx), Some(rx))
+        }
+        None => (None, None),
+    };
+    let notifier = ServiceNotifier::from_env().map(|notifier| Arc::new(Mutex::new(notifier)));
+    let mut daemon = Daemon {
+        handles: Vec::new(),
+        tx,
+        store,
+        recorder,
+        notifier: notifier.clone(),
     };
+    daemon.start(config);
+    if let Some(notifier) = notifier {
+        ServiceNotifier::spawn(notifier);
+    }
 
-    debug!("Something went wrong 😅");
+    // If a UNIX socket is requested its clients are served on their own threads.
+    if let (Some(socket_path), Some(rx)) = (matches.value_of("socket"), rx) {
+        bind_socket_and_listen(socket_path, rx);
+    }
 
-    unreachable!();
+    // The main thread handles signals: SIGHUP reloads the config file, SIGTERM and SIGINT terminate
+    // fand (saving the state first with --state). Without a socket to serve, fand also stops once
+    // all the control loops finished, with an error if one of them stopped on an error.
+    let mut signals = Signals::new([SIGHUP, SIGTERM, SIGINT]).expect("Failed to handle signals");
+    loop {
+        for signal in signals.pending() {
+            match signal {
+                SIGHUP => match matches.value_of("config") {
+                    Some(filename) => daemon.reload(filename, &matches),
+                    None => info!("No config file to reload; keeping the default configuration"),
+                },
+                _ => {
+                    if let Some(path) = state_path {
+                        debug!("Received signal {}; saving state to {}", signal, path);
+                        save_state(&daemon.store, path);
+                    }
+                    std::process::exit(0);
+                }
+            }
+        }
+        if matches.value_of("socket").is_none() && daemon.finished() {
+            break;
+        }
+        std::thread::sleep(SIGNAL_POLL_INTERVAL);
+    }
+    std::process::exit(daemon.finish(state_path));
 }
@@ -0,0 +1,81 @@
+//! Clocks from which the control loop takes the time: the times of samples, the deadlines of the
+//! [Scheduler][crate::scheduler::Scheduler] and the time simulated by a
+//! [Plant][crate::simulation::Plant]. The [SystemClock] follows real time; a [VirtualClock] only
+//! moves forward when waited on, so a control loop driven by it runs as fast as possible (e.g. to
+//! evaluate an hour of simulation in a few seconds).
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A monotonic clock.
+pub trait Clock: Send + Sync {
+    /// Seconds elapsed since the start of the clock.
+    fn now(&self) -> f64;
+
+    /// Block until `now()` reaches `deadline` (in seconds since the start of the clock).
+    fn sleep_until(&self, deadline: f64);
+}
+
+/// A clock shared by the parts of a control loop.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Real (monotonic) time, since the creation of the clock.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+
+    /// A new system clock, ready to be shared.
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock::new())
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn sleep_until(&self, deadline: f64) {
+        let remaining = deadline - self.now();
+        if remaining > 0.0 {
+            thread::sleep(Duration::from_secs_f64(remaining));
+        }
+    }
+}
+
+/// A clock which stands still until it is waited on, and then jumps to the deadline.
+#[derive(Default)]
+pub struct VirtualClock {
+    now: Mutex<f64>,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock::default()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> f64 {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: f64) {
+        let mut now = self.now.lock().unwrap();
+        *now = now.max(deadline);
+    }
+}
@@ -0,0 +1,561 @@
+//! The configuration of fand, which describes either a single [Pipeline] or several named ones
+//! which run concurrently. It can be written in JSON, TOML or YAML (see [Format]); all three share
+//! the same structure. Errors in a configuration are located by the path of the offending field
+//! (e.g. `pipelines.cpu.operations[2].n`), and by line (and column where the parser allows).
+
+use crate::pipeline::Pipeline;
+use crate::validation::{Validate, ValidationError};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Format of a configuration file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Guess the format of a file from its extension: `.toml`, `.yaml` and `.yml` files are TOML
+    /// and YAML respectively, anything else is JSON.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Format {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+
+    /// Parse `text`, keeping track of the path of the value being parsed for error messages.
+    fn parse<T: DeserializeOwned>(self, text: &str) -> Result<T, ConfigError> {
+        match self {
+            Format::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(text);
+                let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+                    let (path, error) = located(err);
+                    ConfigError::Json { path, error }
+                })?;
+                // trailing characters
+                deserializer.end().map_err(|error| ConfigError::Json {
+                    path: String::new(),
+                    error,
+                })?;
+                Ok(value)
+            }
+            Format::Toml => serde_path_to_error::deserialize(toml::Deserializer::new(text))
+                .map_err(|err| {
+                    let (path, error) = located(err);
+                    ConfigError::Toml { path, error }
+                }),
+            Format::Yaml => serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(
+                text,
+            ))
+            .map_err(|err| {
+                let (path, error) = located(err);
+                ConfigError::Yaml { path, error }
+            }),
+        }
+    }
+
+    /// Set the line of every error to that of the field it refers to in `text` (or of the closest
+    /// enclosing field present, e.g. the operation a missing field belongs to).
+    fn locate(self, text: &str, errors: Vec<ValidationError>) -> Vec<ValidationError> {
+        let lines = match self {
+            Format::Toml => toml_lines(text),
+            // JSON is a subset of YAML
+            Format::Json | Format::Yaml => yaml_lines(text),
+        };
+        errors
+            .into_iter()
+            .map(|mut err| {
+                let mut path = err.path.as_str();
+                err.line = loop {
+                    if let Some(line) = lines.get(path) {
+                        break Some(*line);
+                    }
+                    match path.rfind(['.', '[']) {
+                        Some(end) => path = &path[..end],
+                        None => break None,
+                    }
+                };
+                err
+            })
+            .collect()
+    }
+
+    /// Serialize `value` in this format.
+    fn write<T: Serialize>(self, value: &T) -> Result<String, String> {
+        match self {
+            Format::Json => serde_json::to_string_pretty(value)
+                .map(|text| text + "\n")
+                .map_err(|err| err.to_string()),
+            Format::Toml => toml::to_string_pretty(value).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::to_string(value).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// The line of every field of a TOML document, by path (e.g. `operations[2].n`).
+fn toml_lines(text: &str) -> BTreeMap<String, usize> {
+    use toml_edit::{ImDocument, Item, Value};
+
+    fn walk_table<'a>(
+        entries: impl Iterator<Item = (&'a toml_edit::Key, &'a Item)>,
+        path: &str,
+        line: &dyn Fn(Option<std::ops::Range<usize>>) -> Option<usize>,
+        lines: &mut BTreeMap<String, usize>,
+    ) {
+        for (key, item) in entries {
+            let path = if path.is_empty() {
+                key.get().to_string()
+            } else {
+                format!("{}.{}", path, key.get())
+            };
+            if let Some(line) = line(key.span()) {
+                lines.entry(path.clone()).or_insert(line);
+            }
+            walk_item(item, &path, line, lines);
+        }
+    }
+    fn walk_item(
+        item: &Item,
+        path: &str,
+        line: &dyn Fn(Option<std::ops::Range<usize>>) -> Option<usize>,
+        lines: &mut BTreeMap<String, usize>,
+    ) {
+        match item {
+            Item::Table(table) => walk_header(table, path, line, lines),
+            Item::ArrayOfTables(tables) => {
+                for (index, table) in tables.iter().enumerate() {
+                    walk_header(table, &format!("{}[{}]", path, index), line, lines);
+                }
+            }
+            Item::Value(value) => walk_value(value, path, line, lines),
+            Item::None => {}
+        }
+    }
+    fn walk_header(
+        table: &toml_edit::Table,
+        path: &str,
+        line: &dyn Fn(Option<std::ops::Range<usize>>) -> Option<usize>,
+        lines: &mut BTreeMap<String, usize>,
+    ) {
+        if let Some(line) = line(table.span()) {
+            lines.entry(path.to_string()).or_insert(line);
+        }
+        let entries = table.iter().filter_map(|(key, _)| table.get_key_value(key));
+        walk_table(entries, path, line, lines);
+    }
+    fn walk_value(
+        value: &Value,
+        path: &str,
+        line: &dyn Fn(Option<std::ops::Range<usize>>) -> Option<usize>,
+        lines: &mut BTreeMap<String, usize>,
+    ) {
+        if let Some(line) = line(value.span()) {
+            lines.entry(path.to_string()).or_insert(line);
+        }
+        match value {
+            Value::InlineTable(table) => {
+                for (key, value) in table.iter() {
+                    let path = format!("{}.{}", path, key);
+                    if let Some((key, _)) = table.get_key_value(key) {
+                        if let Some(line) = line(key.span()) {
+                            lines.entry(path.clone()).or_insert(line);
+                        }
+                    }
+                    walk_value(value, &path, line, lines);
+                }
+            }
+            Value::Array(array) => {
+                for (index, value) in array.iter().enumerate() {
+                    walk_value(value, &format!("{}[{}]", path, index), line, lines);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut lines = BTreeMap::new();
+    if let Ok(document) = ImDocument::parse(text) {
+        let line = |span: Option<std::ops::Range<usize>>| {
+            span.map(|span| text[..span.start].matches('\n').count() + 1)
+        };
+        let root = document.as_table();
+        let entries = root.iter().filter_map(|(key, _)| root.get_key_value(key));
+        walk_table(entries, "", &line, &mut lines);
+    }
+    lines
+}
+
+/// The line of every field of a YAML (or JSON) document, by path (e.g. `operations[2].n`).
+fn yaml_lines(text: &str) -> BTreeMap<String, usize> {
+    use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+    use yaml_rust::scanner::Marker;
+
+    /// A mapping or sequence being read, with its path.
+    enum Collection {
+        /// Holds the key whose value comes next, if any
+        Mapping(String, Option<String>),
+        Sequence(String, usize),
+    }
+    #[derive(Default)]
+    struct Receiver {
+        stack: Vec<Collection>,
+        lines: BTreeMap<String, usize>,
+    }
+    impl MarkedEventReceiver for Receiver {
+        fn on_event(&mut self, event: Event, mark: Marker) {
+            let starts_value = matches!(
+                event,
+                Event::Scalar(..)
+                    | Event::Alias(_)
+                    | Event::MappingStart(_)
+                    | Event::SequenceStart(_)
+            );
+            if !starts_value {
+                if let Event::MappingEnd | Event::SequenceEnd = event {
+                    self.stack.pop();
+                }
+                return;
+            }
+            // the path of the value (or key) starting here
+            let path = match self.stack.last_mut() {
+                None => String::new(),
+                Some(Collection::Mapping(path, key @ None)) => {
+                    let name = match &event {
+                        Event::Scalar(name, ..) => name.clone(),
+                        _ => "?".to_string(),
+                    };
+                    let path = if path.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", path, name)
+                    };
+                    *key = Some(name);
+                    self.lines.entry(path).or_insert(mark.line());
+                    // a complex key is skipped, as the fields it contains
+                    match event {
+                        Event::MappingStart(_) => {
+                            self.stack.push(Collection::Mapping("?".to_string(), None))
+                        }
+                        Event::SequenceStart(_) => {
+                            self.stack.push(Collection::Sequence("?".to_string(), 0))
+                        }
+                        _ => {}
+                    }
+                    return;
+                }
+                Some(Collection::Mapping(path, key)) => {
+                    let name = key.take().unwrap_or_default();
+                    if path.is_empty() {
+                        name
+                    } else {
+                        format!("{}.{}", path, name)
+                    }
+                }
+                Some(Collection::Sequence(path, index)) => {
+                    *index += 1;
+                    format!("{}[{}]", path, *index - 1)
+                }
+            };
+            self.lines.entry(path.clone()).or_insert(mark.line());
+            match event {
+                Event::MappingStart(_) => self.stack.push(Collection::Mapping(path, None)),
+                Event::SequenceStart(_) => self.stack.push(Collection::Sequence(path, 0)),
+                _ => {}
+            }
+        }
+    }
+
+    let mut receiver = Receiver::default();
+    // the lines found up to an error are still useful
+    let _ = Parser::new(text.chars()).load(&mut receiver, false);
+    receiver.lines
+}
+
+/// Split a parse error into the path of the value where it happened (empty if at the top level)
+/// and the error itself.
+fn located<E>(err: serde_path_to_error::Error<E>) -> (String, E) {
+    let path = err.path().to_string();
+    let path = if path == "." { String::new() } else { path };
+    (path, err.into_inner())
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(format!("unknown configuration format `{}`", s)),
+        }
+    }
+}
+
+/// Errors when reading a configuration. Parse errors come with the path of the value where they
+/// happened (empty if at the top level); the errors themselves give the line and column.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Json {
+        path: String,
+        error: serde_json::Error,
+    },
+    Toml {
+        path: String,
+        error: toml::de::Error,
+    },
+    Yaml {
+        path: String,
+        error: serde_yaml::Error,
+    },
+    /// The configuration could be parsed, but does not describe valid pipelines
+    Invalid(Vec<ValidationError>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (path, error): (&str, &dyn std::fmt::Display) = match self {
+            ConfigError::Io(err) => return write!(f, "{}", err),
+            ConfigError::Json { path, error } => (path, error),
+            ConfigError::Toml { path, error } => (path, error),
+            ConfigError::Yaml { path, error } => (path, error),
+            ConfigError::Invalid(errors) => {
+                let errors: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+                return write!(f, "{}", errors.join("\n"));
+            }
+        };
+        // some parsers (e.g. TOML) end their messages with blank lines
+        let error = error.to_string();
+        if path.is_empty() {
+            write!(f, "{}", error.trim_end())
+        } else {
+            write!(f, "{}: {}", path, error.trim_end())
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Either a single (unnamed) pipeline, e.g. `{"input": ..., "operations": ..., ...}`, or a map of
+/// named pipelines, e.g. `{"pipelines": {"cpu": {"input": ...}, "case": {"input": ...}}}`.
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Config {
+    Multiple {
+        pipelines: BTreeMap<String, Pipeline>,
+    },
+    Single(Box<Pipeline>),
+}
+
+#[derive(serde::Deserialize)]
+struct Multiple {
+    pipelines: BTreeMap<String, Pipeline>,
+}
+
+impl Config {
+    /// Parse a configuration in the given format. The presence of a top level `pipelines` key
+    /// decides which kind of configuration it is, so that errors refer to the right kind. The
+    /// configuration is then [validated][Validate::validate], and all the problems found are
+    /// reported at once.
+    pub fn from_str(text: &str, format: Format) -> Result<Config, ConfigError> {
+        let value: serde_json::Value = format.parse(text)?;
+        let config = if value.get("pipelines").is_some() {
+            let multiple: Multiple = format.parse(text)?;
+            Config::Multiple {
+                pipelines: multiple.pipelines,
+            }
+        } else {
+            Config::Single(Box::new(format.parse(text)?))
+        };
+        let errors = config.validate();
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(format.locate(text, errors)))
+        }
+    }
+
+    /// Read and parse a configuration file; its format is guessed from its extension unless
+    /// given.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        format: Option<Format>,
+    ) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(&path).map_err(ConfigError::Io)?;
+        let format = format.unwrap_or_else(|| Format::from_path(&path));
+        Config::from_str(&text, format)
+    }
+
+    /// Check that the devices and commands used by all pipelines are available (see
+    /// [Pipeline::check_devices]).
+    pub fn check_devices(&self) -> Vec<ValidationError> {
+        self.each_pipeline(Pipeline::check_devices)
+    }
+
+    /// Apply `f` to every pipeline, locating the errors within the configuration.
+    fn each_pipeline<F>(&self, f: F) -> Vec<ValidationError>
+    where
+        F: Fn(&Pipeline) -> Vec<ValidationError>,
+    {
+        let mut errors = Vec::new();
+        for (name, pipeline) in self.pipelines() {
+            errors.extend(f(pipeline).into_iter().map(|err| match name {
+                Some(name) => err.within(&format!("pipelines.{}", name)),
+                None => err,
+            }));
+        }
+        errors
+    }
+
+    /// The pipelines of this configuration together with their names (`None` for a single
+    /// pipeline).
+    pub fn pipelines(&self) -> Vec<(Option<&str>, &Pipeline)> {
+        match self {
+            Config::Multiple { pipelines } => pipelines
+                .iter()
+                .map(|(name, pipeline)| (Some(name.as_str()), pipeline))
+                .collect(),
+            Config::Single(pipeline) => vec![(None, &**pipeline)],
+        }
+    }
+
+    /// Same as [pipelines][Config::pipelines], but taking ownership.
+    pub fn into_pipelines(self) -> Vec<(Option<String>, Pipeline)> {
+        match self {
+            Config::Multiple { pipelines } => pipelines
+                .into_iter()
+                .map(|(name, pipeline)| (Some(name), pipeline))
+                .collect(),
+            Config::Single(pipeline) => vec![(None, *pipeline)],
+        }
+    }
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Vec<ValidationError> {
+        self.each_pipeline(Pipeline::validate)
+    }
+}
+
+/// Set fields of the operation `index` of pipeline `pipeline` (of the single pipeline if `None`)
+/// in the configuration file `path`, e.g. the gains of a PID control, and write the file back in
+/// the same format. A TOML file is edited in place, keeping its comments and formatting; the rest
+/// of a JSON or YAML file is kept as written, but its comments and formatting are lost. The file
+/// is only written if the resulting configuration is valid.
+pub fn set_operation_fields<P: AsRef<Path>>(
+    path: P,
+    format: Option<Format>,
+    pipeline: Option<&str>,
+    index: usize,
+    fields: &[(&str, f64)],
+) -> Result<(), ConfigError> {
+    let text = std::fs::read_to_string(&path).map_err(ConfigError::Io)?;
+    let format = format.unwrap_or_else(|| Format::from_path(&path));
+    let text = match format {
+        Format::Toml => set_toml_operation_fields(&text, pipeline, index, fields)?,
+        Format::Json | Format::Yaml => {
+            set_value_operation_fields(&text, format, pipeline, index, fields)?
+        }
+    };
+    Config::from_str(&text, format)?;
+    std::fs::write(&path, text).map_err(ConfigError::Io)
+}
+
+/// Location of operation `index` of pipeline `pipeline`, for errors.
+fn operation_location(pipeline: Option<&str>, index: usize) -> String {
+    match pipeline {
+        Some(name) => format!("pipelines.{}.operations[{}]", name, index),
+        None => format!("operations[{}]", index),
+    }
+}
+
+/// Same as [set_operation_fields], on the text of a TOML configuration, which is edited in place.
+fn set_toml_operation_fields(
+    text: &str,
+    pipeline: Option<&str>,
+    index: usize,
+    fields: &[(&str, f64)],
+) -> Result<String, ConfigError> {
+    // parse errors are reported the usual way
+    Format::Toml.parse::<toml::Value>(text)?;
+    let mut document: toml_edit::DocumentMut = text
+        .parse()
+        .map_err(|err| ConfigError::Invalid(vec![ValidationError::new("", format!("{}", err))]))?;
+    let item = match pipeline {
+        Some(name) => document
+            .get_mut("pipelines")
+            .and_then(|pipelines| pipelines.get_mut(name)),
+        None => Some(document.as_item_mut()),
+    };
+    let operation = item
+        .and_then(|pipeline| pipeline.get_mut("operations"))
+        .and_then(|operations| operations.get_mut(index))
+        .and_then(|operation| operation.as_table_like_mut())
+        .ok_or_else(|| {
+            ConfigError::Invalid(vec![ValidationError::new(
+                operation_location(pipeline, index),
+                "no such operation",
+            )])
+        })?;
+    for (field, val) in fields {
+        match operation
+            .get_mut(field)
+            .and_then(|item| item.as_value_mut())
+        {
+            // keep the comments around the value
+            Some(value) => {
+                let decor = value.decor().clone();
+                *value = toml_edit::Value::from(*val);
+                *value.decor_mut() = decor;
+            }
+            None => {
+                operation.insert(field, toml_edit::value(*val));
+            }
+        }
+    }
+    Ok(document.to_string())
+}
+
+/// Same as [set_operation_fields], on the text of a JSON or YAML configuration, which is written
+/// again from its parsed value.
+fn set_value_operation_fields(
+    text: &str,
+    format: Format,
+    pipeline: Option<&str>,
+    index: usize,
+    fields: &[(&str, f64)],
+) -> Result<String, ConfigError> {
+    let mut value: serde_json::Value = format.parse(text)?;
+    let pointer = match pipeline {
+        Some(name) => format!(
+            "/pipelines/{}/operations/{}",
+            name.replace('~', "~0").replace('/', "~1"),
+            index
+        ),
+        None => format!("/operations/{}", index),
+    };
+    let operation = value
+        .pointer_mut(&pointer)
+        .and_then(|operation| operation.as_object_mut())
+        .ok_or_else(|| {
+            ConfigError::Invalid(vec![ValidationError::new(
+                operation_location(pipeline, index),
+                "no such operation",
+            )])
+        })?;
+    for (field, val) in fields {
+        operation.insert(field.to_string(), serde_json::json!(val));
+    }
+    format
+        .write(&value)
+        .map_err(|err| ConfigError::Io(std::io::Error::other(err)))
+}
@@ -0,0 +1,449 @@
+//! Pipelines whose operations form a directed acyclic graph rather than a single chain. Every
+//! [Node] of the graph has a name, and refers to the nodes it takes its values from by name; this
+//! allows e.g. to run a fast control on one input and a slow one on another, and take the maximum
+//! of both. Nodes whose output is used by several other nodes are wrapped in a [Tee].
+
+use crate::clock::SharedClock;
+use crate::inputs::{Input, Timestamped};
+use crate::operations::parameters::*;
+use crate::pipeline::{HoldOnError, LoopState};
+use crate::sample::Sample;
+use crate::state::{StateSlot, StateStore};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+/// A node of a graph pipeline.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum Node {
+    /// A source of values
+    Input(Input),
+    /// A series of operations applied to the output of another node
+    Chain {
+        /// Name of the node whose output the operations apply to
+        from: String,
+        operations: Vec<Box<dyn OperationParameters>>,
+    },
+    /// A combination of the outputs of several nodes
+    Join {
+        /// Names of the nodes whose outputs are combined
+        from: Vec<String>,
+        join: Join,
+    },
+}
+
+impl Node {
+    /// Names of the nodes this node takes its values from.
+    fn inputs(&self) -> Vec<&str> {
+        match self {
+            Node::Input(_) => Vec::new(),
+            Node::Chain { from, .. } => vec![from.as_str()],
+            Node::Join { from, .. } => from.iter().map(|name| name.as_str()).collect(),
+        }
+    }
+}
+
+/// How a [Node::Join] combines the values of its inputs.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+pub enum Join {
+    /// The largest of the values
+    Max,
+    /// The smallest of the values
+    Min,
+    /// The sum of the values
+    Sum,
+    /// The value of the input at the given index (the others are still consumed)
+    Select(usize),
+}
+
+/// Errors in the description of a graph pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+    /// A node (or the pipeline itself) refers to a node which does not exist
+    UnknownNode { node: String, from: String },
+    /// The nodes listed form a cycle
+    Cycle(Vec<String>),
+    /// A join has no inputs, or selects an input which does not exist
+    InvalidJoin(String),
+    /// The nodes using the output of a node take its values at different rates (e.g. one of them
+    /// subsamples), so the values would pile up for the slowest of them
+    UnevenConsumers(String),
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::UnknownNode { node, from } => {
+                write!(
+                    f,
+                    "node `{}` takes its input from unknown node `{}`",
+                    node, from
+                )
+            }
+            GraphError::Cycle(nodes) => write!(f, "cycle in graph: {}", nodes.join(" -> ")),
+            GraphError::InvalidJoin(node) => write!(
+                f,
+                "join `{}` has no inputs or selects an input it does not have",
+                node
+            ),
+            GraphError::UnevenConsumers(node) => write!(
+                f,
+                "the output of `{}` is used by nodes which take its values at different rates \
+                 (through a supersample or subsample)",
+                node
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// Check that all the nodes referred to exist, that joins are well formed, and that there are no
+/// cycles. `from` is the node feeding the operations of the pipeline itself.
+pub fn check(nodes: &BTreeMap<String, Node>, from: &str) -> Result<(), GraphError> {
+    if !nodes.contains_key(from) {
+        return Err(GraphError::UnknownNode {
+            node: "(pipeline)".to_string(),
+            from: from.to_string(),
+        });
+    }
+    for (name, node) in nodes {
+        for input in node.inputs() {
+            if !nodes.contains_key(input) {
+                return Err(GraphError::UnknownNode {
+                    node: name.clone(),
+                    from: input.to_string(),
+                });
+            }
+        }
+        if let Node::Join { from, join } = node {
+            let selects_missing = matches!(join, Join::Select(index) if *index >= from.len());
+            if from.is_empty() || selects_missing {
+                return Err(GraphError::InvalidJoin(name.clone()));
+            }
+        }
+    }
+
+    // depth first search; a node which is reached again while still on the path is in a cycle
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark {
+        OnPath,
+        Done,
+    }
+    fn visit<'a>(
+        name: &'a str,
+        nodes: &'a BTreeMap<String, Node>,
+        marks: &mut BTreeMap<&'a str, Mark>,
+        path: &mut Vec<&'a str>,
+    ) -> Result<(), GraphError> {
+        match marks.get(name) {
+            Some(Mark::Done) => return Ok(()),
+            Some(Mark::OnPath) => {
+                let start = path.iter().position(|node| *node == name).unwrap_or(0);
+                let mut cycle: Vec<String> = path[start..].iter().map(|s| s.to_string()).collect();
+                cycle.push(name.to_string());
+                return Err(GraphError::Cycle(cycle));
+            }
+            None => {}
+        }
+        marks.insert(name, Mark::OnPath);
+        path.push(name);
+        for input in nodes[name].inputs() {
+            visit(input, nodes, marks, path)?;
+        }
+        path.pop();
+        marks.insert(name, Mark::Done);
+        Ok(())
+    }
+    let mut marks = BTreeMap::new();
+    for name in nodes.keys() {
+        visit(name, nodes, &mut marks, &mut Vec::new())?;
+    }
+
+    // the output of a node used several times is buffered for the consumers which lag behind, so
+    // they must all take its values at the same rate
+    rates(nodes, from).map(|_| ())
+}
+
+/// How many values are taken from every node which contributes to `from` per value of `from`, as
+/// fractions; fails if the consumers of a node take its values at different rates.
+fn rates<'a>(
+    nodes: &'a BTreeMap<String, Node>,
+    from: &'a str,
+) -> Result<BTreeMap<&'a str, (u64, u64)>, GraphError> {
+    let reachable = reachable(nodes, from);
+    let mut rates: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    rates.insert(from, (1, 1));
+    // a node comes after all its consumers in `reachable` only once they are sorted, so repeat
+    // until every rate is known (the graph has no cycles)
+    while rates.len() < reachable.len() {
+        for name in &reachable {
+            if rates.contains_key(name) {
+                continue;
+            }
+            let consumers: Vec<&str> = reachable
+                .iter()
+                .copied()
+                .filter(|consumer| nodes[*consumer].inputs().contains(name))
+                .collect();
+            if !consumers
+                .iter()
+                .all(|consumer| rates.contains_key(consumer))
+            {
+                continue;
+            }
+            let mut consumer_rates = consumers.iter().map(|consumer| {
+                let (num, den) = rates[consumer];
+                match &nodes[*consumer] {
+                    Node::Chain { operations, .. } => {
+                        let (ops_num, ops_den) = values_taken(operations);
+                        reduce_fraction(num * ops_num, den * ops_den)
+                    }
+                    _ => (num, den),
+                }
+            });
+            let rate = consumer_rates
+                .next()
+                .expect("Reachable nodes have a consumer");
+            if consumer_rates.any(|other| other != rate) {
+                return Err(GraphError::UnevenConsumers(name.to_string()));
+            }
+            rates.insert(name, rate);
+        }
+    }
+    Ok(rates)
+}
+
+/// Names of the nodes which contribute to the output of node `from` (starting with it).
+fn reachable<'a>(nodes: &'a BTreeMap<String, Node>, from: &'a str) -> Vec<&'a str> {
+    let mut reachable = vec![from];
+    let mut index = 0;
+    while index < reachable.len() {
+        for input in nodes[reachable[index]].inputs() {
+            if !reachable.contains(&input) {
+                reachable.push(input);
+            }
+        }
+        index += 1;
+    }
+    reachable
+}
+
+/// Build the iterator producing the output of node `from`; the operations of a node report to
+/// `tx` (if given) with ids `node/index`, prefixed by `prefix`, and keep their state in the slots
+/// of `store` (if given) with the same ids; the outputs of inputs, joins and operations are passed
+/// through `tap` with the same ids too (e.g. to record them). All inputs take the time from
+/// `clock`, and stop at their first error, which is recorded in `state`. A value is taken from
+/// `from` every `period` seconds. Nodes which do not contribute to `from` are not built.
+#[allow(clippy::too_many_arguments)]
+pub fn build(
+    mut nodes: BTreeMap<String, Node>,
+    from: &str,
+    period: f64,
+    prefix: &str,
+    tx: Option<&Sender<String>>,
+    store: Option<&StateStore>,
+    tap: &dyn Fn(BoxedIterator, String) -> BoxedIterator,
+    clock: SharedClock,
+    state: &Arc<Mutex<LoopState>>,
+) -> Result<BoxedIterator, GraphError> {
+    check(&nodes, from)?;
+    let periods = rates(&nodes, from)?
+        .into_iter()
+        .map(|(name, (num, den))| (name.to_string(), period * den as f64 / num as f64))
+        .collect();
+    // count how many times the output of each node is used, among the nodes which contribute to
+    // `from` (a tee whose output is not consumed would buffer forever).
+    let reachable = reachable(&nodes, from);
+    let mut consumers: BTreeMap<String, usize> = BTreeMap::new();
+    consumers.insert(from.to_string(), 1);
+    for name in &reachable {
+        for input in nodes[*name].inputs() {
+            *consumers.entry(input.to_string()).or_insert(0) += 1;
+        }
+    }
+    let mut builder = GraphBuilder {
+        consumers,
+        periods,
+        tees: BTreeMap::new(),
+        clock,
+        state,
+        prefix,
+        tx,
+        store,
+        tap,
+    };
+    Ok(builder.build(from, &mut nodes))
+}
+
+struct GraphBuilder<'a> {
+    consumers: BTreeMap<String, usize>,
+    /// Time between two values taken from each node, in seconds
+    periods: BTreeMap<String, f64>,
+    /// Nodes with several consumers which were already built, with the tees not yet handed out
+    tees: BTreeMap<String, Vec<Tee>>,
+    clock: SharedClock,
+    state: &'a Arc<Mutex<LoopState>>,
+    prefix: &'a str,
+    tx: Option<&'a Sender<String>>,
+    store: Option<&'a StateStore>,
+    tap: &'a dyn Fn(BoxedIterator, String) -> BoxedIterator,
+}
+
+impl<'a> GraphBuilder<'a> {
+    fn monitor(&self, id: String) -> Option<Monitor> {
+        self.tx.map(|tx| Monitor {
+            id: format!("{}{}", self.prefix, id),
+            tx: tx.clone(),
+        })
+    }
+
+    fn slot(&self, id: &str) -> Option<StateSlot> {
+        self.store
+            .map(|store| store.slot(&format!("{}{}", self.prefix, id)))
+    }
+
+    /// Pass the samples of `iter` through the tap, with the given id.
+    fn record(&self, iter: BoxedIterator, id: &str) -> BoxedIterator {
+        (self.tap)(iter, format!("{}{}", self.prefix, id))
+    }
+
+    fn build(&mut self, name: &str, nodes: &mut BTreeMap<String, Node>) -> BoxedIterator {
+        if let Some(tees) = self.tees.get_mut(name) {
+            return Box::new(
+                tees.pop()
+                    .expect("A node is used at most once per consumer"),
+            );
+        }
+        let node = nodes.remove(name).expect("Graph was checked");
+        let iter: BoxedIterator = match node {
+            Node::Input(mut input) => {
+                input.set_clock(&self.clock);
+                let input =
+                    HoldOnError::new(Box::new(input.readings()), Some(0), Arc::clone(self.state));
+                self.record(
+                    Box::new(Timestamped::with_clock(input, self.clock.clone())),
+                    name,
+                )
+            }
+            Node::Chain { from, operations } => {
+                let mut last_iterator = self.build(&from, nodes);
+                let period = self.periods[name];
+                let periods: Vec<f64> = (0..operations.len())
+                    .map(|index| {
+                        let (calls, ticks) = values_taken(&operations[index + 1..]);
+                        period * ticks as f64 / calls as f64
+                    })
+                    .collect();
+                for (index, mut operation) in operations.into_iter().enumerate() {
+                    operation.set_period(periods[index]);
+                    let id = format!("{}/{}", name, index);
+                    let monitor = self.monitor(id.clone());
+                    last_iterator = operation.build(last_iterator, monitor, self.slot(&id));
+                    last_iterator = self.record(last_iterator, &id);
+                }
+                last_iterator
+            }
+            Node::Join { from, join } => {
+                let joined = Joined {
+                    inputs: from.iter().map(|input| self.build(input, nodes)).collect(),
+                    join,
+                    monitor: self.monitor(name.to_string()),
+                };
+                self.record(Box::new(joined), name)
+            }
+        };
+        match self.consumers.get(name) {
+            Some(&count) if count > 1 => {
+                let mut tees = Tee::split(iter, count);
+                let first = tees.pop().unwrap();
+                self.tees.insert(name.to_string(), tees);
+                Box::new(first)
+            }
+            _ => iter,
+        }
+    }
+}
+
+struct TeeShared {
+    source: BoxedIterator,
+    buffers: Vec<VecDeque<Sample>>,
+}
+
+/// One of several iterators producing the same samples as a shared source. Samples pulled from the
+/// source by one of them are buffered for the others; the buffers grow without limit if the
+/// consumers pull at different rates (e.g. if one of them subsamples), which [check] rejects.
+pub struct Tee {
+    shared: Arc<Mutex<TeeShared>>,
+    index: usize,
+}
+
+impl Tee {
+    pub fn split(source: BoxedIterator, count: usize) -> Vec<Tee> {
+        let shared = Arc::new(Mutex::new(TeeShared {
+            source,
+            buffers: vec![VecDeque::new(); count],
+        }));
+        (0..count)
+            .map(|index| Tee {
+                shared: Arc::clone(&shared),
+                index,
+            })
+            .collect()
+    }
+}
+
+impl Iterator for Tee {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(sample) = shared.buffers[self.index].pop_front() {
+            return Some(sample);
+        }
+        let sample = shared.source.next()?;
+        for (index, buffer) in shared.buffers.iter_mut().enumerate() {
+            if index != self.index {
+                buffer.push_back(sample);
+            }
+        }
+        Some(sample)
+    }
+}
+
+/// Iterator combining one sample of each of its inputs; the time of the result is the latest of
+/// the times of the samples combined.
+struct Joined {
+    inputs: Vec<BoxedIterator>,
+    join: Join,
+    monitor: Option<Monitor>,
+}
+
+impl Iterator for Joined {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let samples = self
+            .inputs
+            .iter_mut()
+            .map(|input| input.next())
+            .collect::<Option<Vec<Sample>>>()?;
+        let values = samples.iter().map(|sample| sample.value);
+        let value = match self.join {
+            Join::Max => values.fold(f64::NEG_INFINITY, f64::max),
+            Join::Min => values.fold(f64::INFINITY, f64::min),
+            Join::Sum => values.sum(),
+            Join::Select(index) => samples[index].value,
+        };
+        let time = samples
+            .iter()
+            .map(|sample| sample.time)
+            .fold(f64::NEG_INFINITY, f64::max);
+        if let Some(monitor) = &self.monitor {
+            monitor.send(format!(">:{}\n", value));
+        }
+        Some(Sample::new(value, time))
+    }
+}
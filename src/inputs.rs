@@ -1,43 +1,226 @@
-use log::debug;
+use crate::clock::{SharedClock, SystemClock};
+use crate::recording::Replay;
+use crate::sample::Sample;
+use crate::simulation::{Fans, Plant};
+use crate::tachometer::Tachometer;
+use crate::validation::{Validate, ValidationError};
+use log::{debug, error};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::Path;
 use std::process::Command;
 
+/// File from which the temperature of the CPU of a Raspberry Pi is read (in thousandths of °C).
+const RPI_CPU_TEMP: &str = "/sys/class/thermal/thermal_zone0/temp";
+
 /// An input to the control loop.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub enum Input {
+    /// Temperature of the CPU of a Raspberry Pi, in °C
     RPiCpuTemp,
+    /// Value printed by a command, run once per sample
     External(String),
+    /// Temperature of a simulated thermal plant (see [simulation][crate::simulation])
+    Simulated(Box<Plant>),
+    /// Values of a recording (see [recording][crate::recording])
+    Replay(Box<Replay>),
+    /// Speed of a fan, in RPM (see [tachometer][crate::tachometer])
+    Tachometer(Box<Tachometer>),
 }
 
-impl Iterator for Input {
-    type Item = f64;
+impl Input {
+    /// Check that the device or command this input reads from is available.
+    pub fn check_device(&self) -> Result<(), String> {
+        match self {
+            Input::RPiCpuTemp => fs::metadata(RPI_CPU_TEMP)
+                .map(|_| ())
+                .map_err(|err| format!("cannot read {}: {}", RPI_CPU_TEMP, err)),
+            Input::External(cmd) => find_command(cmd),
+            Input::Simulated(_) => Ok(()),
+            Input::Replay(replay) => replay.check(),
+            Input::Tachometer(tachometer) => tachometer.check_device(),
+        }
+    }
 
-    #[inline]
-    fn next(&mut self) -> Option<f64> {
+    /// Take the time from `clock` (only simulated, replayed and tachometer inputs depend on the
+    /// time).
+    pub fn set_clock(&mut self, clock: &SharedClock) {
+        match self {
+            Input::Simulated(plant) => plant.set_clock(clock.clone()),
+            Input::Replay(replay) => replay.set_clock(clock.clone()),
+            Input::Tachometer(tachometer) => tachometer.set_clock(clock.clone()),
+            _ => {}
+        }
+    }
+
+    /// Connect simulated inputs to `fans` (see [Fans]).
+    pub fn set_fans(&mut self, fans: &Fans) {
+        match self {
+            Input::Simulated(plant) => plant.set_fans(fans),
+            Input::Tachometer(tachometer) => tachometer.set_fans(fans),
+            _ => {}
+        }
+    }
+}
+
+impl Validate for Input {
+    fn validate(&self) -> Vec<ValidationError> {
+        match self {
+            Input::Simulated(plant) => plant
+                .params()
+                .validate()
+                .into_iter()
+                .map(|err| err.within("Simulated"))
+                .collect(),
+            Input::Replay(replay) => replay
+                .params()
+                .validate()
+                .into_iter()
+                .map(|err| err.within("Replay"))
+                .collect(),
+            Input::Tachometer(tachometer) => tachometer
+                .params()
+                .validate()
+                .into_iter()
+                .map(|err| err.within("Tachometer"))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Check that `cmd` can be run: either it is a path to an executable file, or an executable file
+/// of that name is in the `PATH`.
+pub(crate) fn find_command(cmd: &str) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    let found = if cmd.contains('/') {
+        is_executable(Path::new(cmd))
+    } else {
+        std::env::var_os("PATH")
+            .map(|paths| std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(cmd))))
+            .unwrap_or(false)
+    };
+    if found {
+        Ok(())
+    } else {
+        Err(format!("command `{}` not found or not executable", cmd))
+    }
+}
+
+impl Input {
+    /// Read the next value of this input; `None` once it is exhausted (e.g. at the end of a
+    /// recording), and an error if it could not be read this time.
+    pub fn read(&mut self) -> Option<Result<f64, String>> {
         match self {
             Input::RPiCpuTemp => {
-                let file_content = fs::read_to_string("/sys/class/thermal/thermal_zone0/temp").ok();
-                let the_temp = file_content
-                    .and_then(|s| s.trim().parse::<f64>().ok())
+                let the_temp = fs::read_to_string(RPI_CPU_TEMP)
+                    .map_err(|err| format!("cannot read {}: {}", RPI_CPU_TEMP, err))
+                    .and_then(|s| {
+                        s.trim()
+                            .parse::<f64>()
+                            .map_err(|err| format!("cannot parse {}: {}", RPI_CPU_TEMP, err))
+                    })
                     .map(|x| x / 1_000_f64);
                 debug!("Temperature is: {:2.2?}", the_temp);
-                the_temp
+                Some(the_temp)
             }
             Input::External(cmd) => {
                 // TODO: rudimentary implementation for testing purposes
-                let command_output = Command::new(cmd)
+                let value = Command::new(&*cmd)
                     .output()
-                    .expect("External input command failed");
-                let output_string = String::from_utf8(command_output.stdout)
-                    .expect("Failed to parse external input as string");
-                Some(
-                    output_string
-                        .trim()
-                        .parse::<f64>()
-                        .expect("Failed to parse external input as float"),
-                )
+                    .map_err(|err| format!("cannot run `{}`: {}", cmd, err))
+                    .and_then(|output| {
+                        String::from_utf8(output.stdout)
+                            .map_err(|err| format!("`{}` printed {}", cmd, err))
+                    })
+                    .and_then(|output| {
+                        output.trim().parse::<f64>().map_err(|err| {
+                            format!(
+                                "cannot parse the output of `{}` ({}): {}",
+                                cmd,
+                                output.trim(),
+                                err
+                            )
+                        })
+                    });
+                Some(value)
+            }
+            Input::Simulated(plant) => plant.next().map(Ok),
+            Input::Replay(replay) => replay.next().map(Ok),
+            Input::Tachometer(tachometer) => Some(tachometer.read()),
+        }
+    }
+
+    /// The values of this input, including the errors (see [read][Input::read]).
+    pub fn readings(self) -> Readings {
+        Readings(self)
+    }
+}
+
+/// The values of an [Input], as [read][Input::read].
+pub struct Readings(Input);
+
+impl Iterator for Readings {
+    type Item = Result<f64, String>;
+
+    fn next(&mut self) -> Option<Result<f64, String>> {
+        self.0.read()
+    }
+}
+
+/// As an iterator, an input ends at the first error (which is logged); see [Input::readings] to
+/// carry on.
+impl Iterator for Input {
+    type Item = f64;
+
+    #[inline]
+    fn next(&mut self) -> Option<f64> {
+        match self.read()? {
+            Ok(value) => Some(value),
+            Err(err) => {
+                error!("Failed to read input: {}", err);
+                None
             }
         }
     }
 }
+
+/// Iterator adaptor that stamps every value of an input with the time (in seconds) elapsed since
+/// the adaptor was created (or the time of a given clock).
+pub struct Timestamped<I> {
+    input: I,
+    clock: SharedClock,
+}
+
+impl<I> Timestamped<I>
+where
+    I: Iterator<Item = f64>,
+{
+    pub fn new(input: I) -> Timestamped<I> {
+        Timestamped::with_clock(input, SystemClock::shared())
+    }
+
+    /// Stamp values with the time of `clock` (e.g. to share it among several inputs).
+    pub fn with_clock(input: I, clock: SharedClock) -> Timestamped<I> {
+        Timestamped { input, clock }
+    }
+}
+
+impl<I> Iterator for Timestamped<I>
+where
+    I: Iterator<Item = f64>,
+{
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        let value = self.input.next()?;
+        Some(Sample::new(value, self.clock.now()))
+    }
+}
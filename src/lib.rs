@@ -1,4 +1,23 @@
+pub mod alarms;
+pub mod clock;
+pub mod config;
+pub mod graph;
 pub mod inputs;
 pub mod operations;
 pub mod outputs;
 pub mod pipeline;
+pub mod recording;
+pub mod sample;
+pub mod scheduler;
+pub mod simulation;
+pub mod state;
+pub mod systemd;
+pub mod tachometer;
+pub mod tuning;
+pub mod validation;
+
+// used by register_operation!
+#[doc(hidden)]
+pub use inventory;
+#[doc(hidden)]
+pub use schemars;
@@ -0,0 +1,219 @@
+//! A PID controller which, unlike the one in the [pid](https://crates.io/crates/pid) crate, knows
+//! about the range of its output; this allows it to implement anti-windup, and to change its
+//! setpoint or gains at runtime without a jump in the output (bumpless transfer).
+
+use serde::{Deserialize, Serialize};
+
+use super::parameters::{AntiWindup, Direction};
+
+/// The output of a single update of the controller, split in its terms.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlOutput {
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+    /// `bias + p + i + d`, clamped to the output range of the controller
+    pub output: f64,
+}
+
+/// A PID controller with derivative on measurement. The sign of the error is chosen according to
+/// the [Direction] of the controller, so that all terms are expressed in output units and simply
+/// add up to the output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PidController {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    setpoint: f64,
+    direction: Direction,
+    bias: f64,
+    output_min: f64,
+    output_max: f64,
+    i_limit: Option<f64>,
+    anti_windup: AntiWindup,
+    derivative_filter: Option<f64>,
+    // state
+    integral_term: f64,
+    derivative_term: f64,
+    prev_measurement: Option<f64>,
+}
+
+impl PidController {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        kp: f64,
+        ki: f64,
+        kd: f64,
+        setpoint: f64,
+        direction: Direction,
+        bias: f64,
+        output_min: f64,
+        output_max: f64,
+    ) -> Self {
+        PidController {
+            kp,
+            ki,
+            kd,
+            setpoint,
+            direction,
+            bias,
+            output_min,
+            output_max,
+            i_limit: None,
+            anti_windup: AntiWindup::Off,
+            derivative_filter: None,
+            integral_term: 0.0,
+            derivative_term: 0.0,
+            prev_measurement: None,
+        }
+    }
+
+    /// Limit the absolute value of the integral term.
+    pub fn with_i_limit(mut self, i_limit: Option<f64>) -> Self {
+        self.i_limit = i_limit;
+        self
+    }
+
+    /// Select the anti-windup strategy.
+    pub fn with_anti_windup(mut self, anti_windup: AntiWindup) -> Self {
+        self.anti_windup = anti_windup;
+        self
+    }
+
+    /// Low-pass filter the derivative term with the given time constant.
+    pub fn with_derivative_filter(mut self, time_constant: Option<f64>) -> Self {
+        self.derivative_filter = time_constant;
+        self
+    }
+
+    pub fn setpoint(&self) -> f64 {
+        self.setpoint
+    }
+
+    /// Change the setpoint; the integral term absorbs the change in the proportional term so
+    /// that the output does not jump.
+    pub fn set_setpoint(&mut self, setpoint: f64) {
+        let delta_p = self.kp * self.sign() * (setpoint - self.setpoint);
+        self.setpoint = setpoint;
+        self.integral_term -= delta_p;
+    }
+
+    /// Change the gains; the integral term absorbs the change in the proportional and derivative
+    /// terms so that the output does not jump.
+    pub fn set_gains(&mut self, kp: f64, ki: f64, kd: f64) {
+        if let Some(measurement) = self.prev_measurement {
+            let error = self.error(measurement);
+            let old_d = self.derivative_term;
+            let new_d = if self.kd != 0.0 {
+                old_d * kd / self.kd
+            } else {
+                0.0
+            };
+            self.integral_term += (self.kp - kp) * error + (old_d - new_d);
+            self.derivative_term = new_d;
+        }
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    /// Carry on from the state of `previous` (e.g. the controller this one replaces), switching to
+    /// the setpoint and gains of this one with [set_setpoint][PidController::set_setpoint] and
+    /// [set_gains][PidController::set_gains], so that the output does not jump. The state is not
+    /// carried over if the direction changed.
+    pub fn resume_from(&mut self, previous: &PidController) {
+        if previous.direction != self.direction {
+            return;
+        }
+        let mut resumed = PidController {
+            kp: previous.kp,
+            ki: previous.ki,
+            kd: previous.kd,
+            setpoint: previous.setpoint,
+            integral_term: previous.integral_term,
+            derivative_term: previous.derivative_term,
+            prev_measurement: previous.prev_measurement,
+            ..self.clone()
+        };
+        resumed.set_setpoint(self.setpoint);
+        resumed.set_gains(self.kp, self.ki, self.kd);
+        resumed.integral_term = resumed.limit_integral(resumed.integral_term);
+        *self = resumed;
+    }
+
+    fn sign(&self) -> f64 {
+        match self.direction {
+            Direction::Direct => 1.0,
+            Direction::Reverse => -1.0,
+        }
+    }
+
+    fn error(&self, measurement: f64) -> f64 {
+        self.sign() * (self.setpoint - measurement)
+    }
+
+    fn clamp(&self, val: f64) -> f64 {
+        val.max(self.output_min).min(self.output_max)
+    }
+
+    fn limit_integral(&self, val: f64) -> f64 {
+        match self.i_limit {
+            Some(limit) => val.max(-limit).min(limit),
+            None => val,
+        }
+    }
+
+    /// Compute the next output of the controller given a new measurement taken `dt` after the
+    /// previous one.
+    pub fn update(&mut self, measurement: f64, dt: f64) -> ControlOutput {
+        let error = self.error(measurement);
+        let p = self.kp * error;
+
+        let raw_d = match self.prev_measurement {
+            Some(prev) if dt > 0.0 => -self.sign() * self.kd * (measurement - prev) / dt,
+            _ => 0.0,
+        };
+        self.prev_measurement = Some(measurement);
+        self.derivative_term = match self.derivative_filter {
+            Some(tf) if tf > 0.0 => {
+                let alpha = dt / (tf + dt);
+                self.derivative_term + alpha * (raw_d - self.derivative_term)
+            }
+            _ => raw_d,
+        };
+        let d = self.derivative_term;
+
+        let increment = self.ki * error * dt;
+        self.integral_term = match self.anti_windup {
+            AntiWindup::Off => self.integral_term + increment,
+            AntiWindup::Clamping => {
+                // conditional integration: stop integrating while the output is saturated and
+                // the error would drive it further into saturation.
+                let candidate = self.integral_term + increment;
+                let unclamped = self.bias + p + candidate + d;
+                if (unclamped > self.output_max && increment > 0.0)
+                    || (unclamped < self.output_min && increment < 0.0)
+                {
+                    self.integral_term
+                } else {
+                    candidate
+                }
+            }
+            AntiWindup::BackCalculation { tracking_gain } => {
+                // feed back the difference between the clamped and the unclamped output
+                let unclamped = self.bias + p + self.integral_term + d;
+                let saturation = self.clamp(unclamped) - unclamped;
+                self.integral_term + increment + tracking_gain * saturation * dt
+            }
+        };
+        self.integral_term = self.limit_integral(self.integral_term);
+        let i = self.integral_term;
+
+        ControlOutput {
+            p,
+            i,
+            d,
+            output: self.clamp(self.bias + p + i + d),
+        }
+    }
+}
@@ -4,22 +4,60 @@
 //! kind of approach: For each possible operation there is a struct *`OperationName`*`Parameters`
 //! which contain the parameters that define the operation itself. These structs implement a common
 //! trait -[Operation][parameters::Operation]- which takes the parameters and an input iterator, to
-//! produce the new iterator that applies the corresponding operation. There is also an enum over
-//! the different parameter structs to facilitate serialization / deserialization.
+//! produce the new iterator that applies the corresponding operation. Each operation is then
+//! registered under a name with [register_operation][crate::register_operation], which makes it
+//! available as a `Box<dyn OperationParameters>` to facilitate serialization / deserialization.
 
 use parameters::*;
 
 // export the parameters under the operations module
 pub mod parameters;
 
+pub mod controller;
+
 use serde::Serialize;
 
 use log::debug;
 use tracing::{event, Level};
 
+use crate::register_operation;
+use crate::sample::Sample;
+use crate::state::{restore_field, Persistent};
+use controller::PidController;
 use pid::Pid;
+use serde_json::Value;
+use std::collections::VecDeque;
 use std::iter::Fuse;
 
+/// Time step to use by an operation for a new `sample`: either the `fixed` one if given, or the
+/// time elapsed since the previous sample (zero for the first one). Keeps track of the time of the
+/// previous sample in `last_time`.
+fn step(fixed: Option<f64>, last_time: &mut Option<f64>, sample: &Sample) -> f64 {
+    let elapsed = last_time.map_or(0.0, |last| sample.time - last);
+    *last_time = Some(sample.time);
+    fixed.unwrap_or(elapsed)
+}
+
+/// Restore the window of a running operation over `n` values, which writes its next value at
+/// `index` once full (helper for implementations of [Persistent::restore]). Only the latest `n`
+/// values are kept if the window was saved with a larger `n`.
+fn restore_window(state: &Value, n: usize, index: &mut usize, prev_vals: &mut Vec<f64>) {
+    let mut saved_index = 0;
+    let mut saved: Vec<f64> = Vec::new();
+    restore_field(state, "index", &mut saved_index);
+    restore_field(state, "prev_vals", &mut saved);
+    if saved.is_empty() {
+        return;
+    }
+    // oldest value first, so that the next value is written at 0 once full
+    let oldest = saved_index % saved.len();
+    saved.rotate_left(oldest);
+    let excess = saved.len().saturating_sub(n.max(1));
+    saved.drain(..excess);
+    *prev_vals = saved;
+    *index = 0;
+}
+
 /// The identity operation.
 #[derive(Debug, Serialize)]
 pub struct Identity<I>
@@ -28,8 +66,6 @@ where
 {
     #[serde(skip_serializing)]
     iter: Fuse<I>,
-    #[serde(skip_serializing)]
-    monitor: Option<Monitor>,
 }
 
 impl<I> Iterator for Identity<I>
@@ -48,14 +84,16 @@ impl<I> Operation<I, Identity<I>> for IdentityParameters
 where
     I: Iterator,
 {
-    fn apply(self, iter: I, monitor: Option<Monitor>) -> Identity<I> {
-        Identity {
-            iter: iter.fuse(),
-            monitor,
-        }
+    // the identity has no internal state to report, so the monitor is not used
+    fn apply(self, iter: I, _monitor: Option<Monitor>) -> Identity<I> {
+        Identity { iter: iter.fuse() }
     }
 }
 
+register_operation!("identity", IdentityParameters);
+
+impl<I> Persistent for Identity<I> where I: Iterator {}
+
 /// A PID control operation.
 #[derive(Debug, Serialize)]
 pub struct PID<I>
@@ -72,13 +110,14 @@ where
 
 impl<I> Iterator for PID<I>
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     type Item = I::Item;
 
     #[inline]
     fn next(&mut self) -> Option<I::Item> {
-        if let Some(val) = self.iter.next() {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
             let control = self.pid.next_control_output(val);
             let output = {
                 let p = if control.p.is_sign_negative() {
@@ -96,18 +135,18 @@ where
                 } else {
                     0.
                 };
-                self.monitor.as_ref().and_then(|monitor| {
-                    Some(monitor.send(format!(
+                if let Some(monitor) = &self.monitor {
+                    monitor.send(format!(
                         "PID: {{\"P\": {}, \"I\": {}, \"D\": {}}}\n",
                         p, i, d
-                    )))
-                });
+                    ));
+                }
                 let sum = (p + i + d) as u32;
-                (self.offset + std::cmp::max(0, std::cmp::min(100, sum))) as f64
+                (self.offset + sum.clamp(0, 100)) as f64
             };
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!(">:{}\n", output))));
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", output));
+            }
             let serialized: String = serde_json::to_string(&self).unwrap();
             event!(
                 Level::TRACE,
@@ -117,7 +156,7 @@ where
                 serialized
             );
             //self.monitor.as_ref().and_then(|monitor| Some(monitor.send(format!("PID: {}\n", serialized))));
-            Some(output)
+            Some(sample.with_value(output))
         } else {
             None
         }
@@ -126,18 +165,134 @@ where
 
 impl<I> Operation<I, PID<I>> for PIDParameters
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     fn apply(self, iter: I, monitor: Option<Monitor>) -> PID<I> {
         PID {
             iter: iter.fuse(),
-            pid: self.pid,
+            pid: self.pid(),
             offset: self.offset,
             monitor,
         }
     }
 }
 
+register_operation!("pid", PIDParameters);
+
+impl<I> Persistent for PID<I>
+where
+    I: Iterator,
+{
+    /// Only the state of the control is restored; its gains, limits and setpoint remain those
+    /// configured.
+    fn restore(&mut self, state: &Value) {
+        let configured = self.pid;
+        restore_field(state, "pid", &mut self.pid);
+        self.pid.kp = configured.kp;
+        self.pid.ki = configured.ki;
+        self.pid.kd = configured.kd;
+        self.pid.p_limit = configured.p_limit;
+        self.pid.i_limit = configured.i_limit;
+        self.pid.d_limit = configured.d_limit;
+        self.pid.setpoint = configured.setpoint;
+    }
+}
+
+/// A PID control operation which keeps the sign of the control terms.
+#[derive(Debug, Serialize)]
+pub struct SignedPID<I>
+where
+    I: Iterator,
+{
+    #[serde(skip_serializing)]
+    iter: Fuse<I>,
+    controller: PidController,
+    dt: Option<f64>,
+    last_time: Option<f64>,
+    #[serde(skip_serializing)]
+    monitor: Option<Monitor>,
+}
+
+impl<I> Iterator for SignedPID<I>
+where
+    I: Iterator<Item = Sample>,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
+            let dt = step(self.dt, &mut self.last_time, &sample);
+            let control = self.controller.update(val, dt);
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(
+                    "SignedPID: {{\"P\": {}, \"I\": {}, \"D\": {}}}\n",
+                    control.p, control.i, control.d
+                ));
+            }
+            let output = control.output;
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", output));
+            }
+            let serialized: String = serde_json::to_string(&self).unwrap();
+            event!(
+                Level::TRACE,
+                category = "monitoring",
+                operation = "SignedPID",
+                "{}",
+                serialized
+            );
+            Some(sample.with_value(output))
+        } else {
+            None
+        }
+    }
+}
+
+impl<I> Operation<I, SignedPID<I>> for SignedPIDParameters
+where
+    I: Iterator<Item = Sample>,
+{
+    fn apply(self, iter: I, monitor: Option<Monitor>) -> SignedPID<I> {
+        let controller = PidController::new(
+            self.kp,
+            self.ki,
+            self.kd,
+            self.setpoint,
+            self.direction,
+            self.bias,
+            self.output_min,
+            self.output_max,
+        )
+        .with_i_limit(self.i_limit)
+        .with_anti_windup(self.anti_windup)
+        .with_derivative_filter(self.derivative_filter);
+        SignedPID {
+            iter: iter.fuse(),
+            controller,
+            dt: self.dt,
+            last_time: None,
+            monitor,
+        }
+    }
+}
+
+register_operation!("signed_pid", SignedPIDParameters);
+
+impl<I> Persistent for SignedPID<I>
+where
+    I: Iterator,
+{
+    /// The controller resumes from the restored state, with the gains and setpoint configured
+    /// (see [PidController::resume_from]).
+    fn restore(&mut self, state: &Value) {
+        let mut previous = self.controller.clone();
+        restore_field(state, "controller", &mut previous);
+        self.controller.resume_from(&previous);
+    }
+}
+
 /// A (critically) dampened oscillator operation.
 #[derive(Debug, Serialize)]
 pub struct DampenedOscillator<I>
@@ -148,7 +303,8 @@ where
     iter: Fuse<I>,
     m: f64,
     k: f64,
-    dt: f64,
+    dt: Option<f64>,
+    last_time: Option<f64>,
     target: f64,
     c: f64, // should not be manually set! but we save it to don't have to calculate sqrt every time step
     pos: f64,
@@ -160,18 +316,20 @@ where
 
 impl<I> Iterator for DampenedOscillator<I>
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     type Item = I::Item;
 
     #[inline]
     fn next(&mut self) -> Option<I::Item> {
-        if let Some(val) = self.iter.next() {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
             self.target = val;
+            let dt = step(self.dt, &mut self.last_time, &sample);
 
-            let acc = -1.0 * self.k * (self.pos - self.target) - self.c * self.vel;
-            let new_pos = self.pos + self.dt * self.vel + 0.5 * self.dt * self.dt * self.acc;
-            let fac = self.dt / (2.0 * self.m);
+            let acc = -self.k * (self.pos - self.target) - self.c * self.vel;
+            let new_pos = self.pos + dt * self.vel + 0.5 * dt * dt * self.acc;
+            let fac = dt / (2.0 * self.m);
             let new_vel = 1.0 / (1.0 + self.c * fac)
                 * (self.vel * (1.0 - self.c * fac) + fac * (self.acc - acc));
             self.acc = acc;
@@ -190,14 +348,14 @@ where
                 },
                 serialized
             );
-            self.monitor.as_ref().and_then(|monitor| {
-                Some(monitor.send(format!("DampenedOscillator: {}\n", serialized)))
-            });
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!(">:{}\n", new_pos))));
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("DampenedOscillator: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", new_pos));
+            }
 
-            Some(new_pos)
+            Some(sample.with_value(new_pos))
         } else {
             None
         }
@@ -206,7 +364,7 @@ where
 
 impl<I> Operation<I, DampenedOscillator<I>> for DampenedOscillatorParameters
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     fn apply(self, iter: I, monitor: Option<Monitor>) -> DampenedOscillator<I> {
         let cc = 2_f64 * (self.k * self.m).sqrt();
@@ -215,6 +373,7 @@ where
             m: self.m,
             k: self.k,
             dt: self.dt,
+            last_time: None,
             target: 100.0,
             c: cc,
             pos: 100.0,
@@ -225,6 +384,20 @@ where
     }
 }
 
+register_operation!("dampened_oscillator", DampenedOscillatorParameters);
+
+impl<I> Persistent for DampenedOscillator<I>
+where
+    I: Iterator,
+{
+    fn restore(&mut self, state: &Value) {
+        restore_field(state, "target", &mut self.target);
+        restore_field(state, "pos", &mut self.pos);
+        restore_field(state, "vel", &mut self.vel);
+        restore_field(state, "acc", &mut self.acc);
+    }
+}
+
 /// A clipping operation.
 #[derive(Debug, Serialize)]
 pub struct Clip<I>
@@ -233,32 +406,23 @@ where
 {
     #[serde(skip_serializing)]
     iter: Fuse<I>,
-    max: u64,
-    min: u64,
+    max: f64,
+    min: f64,
     #[serde(skip_serializing)]
     monitor: Option<Monitor>,
 }
 
 impl<I> Iterator for Clip<I>
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     type Item = I::Item;
 
     #[inline]
     fn next(&mut self) -> Option<I::Item> {
-        if let Some(val) = self.iter.next() {
-            // Clip and ordering not implemented for f64; so we round up. Here we assume we are
-            // generally dealing with values between 0 and 100...
-            let mut tmp: u64 = (val * 1000.) as u64; // get up to a thoudansth of the value
-            if tmp > self.max {
-                tmp = self.max;
-            }
-            if tmp < self.min {
-                tmp = self.min;
-            }
-
-            let out: f64 = (tmp as f64) / 1000.;
+        if let Some(sample) = self.iter.next() {
+            // unlike f64::clamp, this does not panic if min > max (the max wins)
+            let out = sample.value.max(self.min).min(self.max);
 
             let serialized: String = serde_json::to_string(&self).unwrap();
             event!(
@@ -268,14 +432,14 @@ where
                 "{}",
                 serialized
             );
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!("Clip: {}\n", serialized))));
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!(">:{}\n", out))));
-
-            Some(out)
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("Clip: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", out));
+            }
+
+            Some(sample.with_value(out))
         } else {
             None
         }
@@ -284,18 +448,22 @@ where
 
 impl<I> Operation<I, Clip<I>> for ClipParameters
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     fn apply(self, iter: I, monitor: Option<Monitor>) -> Clip<I> {
         Clip {
             iter: iter.fuse(),
-            max: (self.max * 1000.) as u64,
-            min: (self.min * 1000.) as u64,
+            max: self.max,
+            min: self.min,
             monitor,
         }
     }
 }
 
+register_operation!("clip", ClipParameters);
+
+impl<I> Persistent for Clip<I> where I: Iterator {}
+
 /// An operation which returns `x` if `x` is at least some value, `0` otherwise.
 #[derive(Debug, Serialize)]
 pub struct AtLeast<I>
@@ -304,28 +472,25 @@ where
 {
     #[serde(skip_serializing)]
     iter: Fuse<I>,
-    val: u64,
+    val: f64,
     #[serde(skip_serializing)]
     monitor: Option<Monitor>,
 }
 
 impl<I> Iterator for AtLeast<I>
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     type Item = I::Item;
 
     #[inline]
     fn next(&mut self) -> Option<I::Item> {
-        if let Some(val) = self.iter.next() {
-            // Clip and ordering not implemented for f64; so we round up. Here we assume we are
-            // generally dealing with values between 0 and 100...
-            let mut tmp: u64 = (val * 1000.) as u64; // get up to a thoudansth of the value
-            if tmp < self.val {
-                tmp = 0;
-            }
-
-            let out: f64 = (tmp as f64) / 1000.;
+        if let Some(sample) = self.iter.next() {
+            let out = if sample.value < self.val {
+                0.
+            } else {
+                sample.value
+            };
 
             let serialized: String = serde_json::to_string(&self).unwrap();
             event!(
@@ -335,14 +500,14 @@ where
                 "{}",
                 serialized
             );
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!("AtLeast: {}\n", serialized))));
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!(">:{}\n", out))));
-
-            Some(out)
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("AtLeast: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", out));
+            }
+
+            Some(sample.with_value(out))
         } else {
             None
         }
@@ -351,17 +516,21 @@ where
 
 impl<I> Operation<I, AtLeast<I>> for AtLeastParameters
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     fn apply(self, iter: I, monitor: Option<Monitor>) -> AtLeast<I> {
         AtLeast {
             iter: iter.fuse(),
-            val: (self.val * 1000.) as u64,
+            val: self.val,
             monitor,
         }
     }
 }
 
+register_operation!("at_least", AtLeastParameters);
+
+impl<I> Persistent for AtLeast<I> where I: Iterator {}
+
 /// A super-sampling operation.
 #[derive(Debug, Serialize)]
 pub struct Supersample<I>
@@ -372,14 +541,19 @@ where
     iter: Fuse<I>,
     n: usize,
     count: usize,
-    last_val: Option<f64>,
+    last_val: Option<Sample>,
+    /// Time between two repeated samples, if set by the pipeline
+    period: Option<f64>,
+    /// Otherwise, the time between the last two values of the input divided by `n` (0 until two
+    /// values were taken)
+    step: f64,
     #[serde(skip_serializing)]
     monitor: Option<Monitor>,
 }
 
 impl<I> Iterator for Supersample<I>
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     type Item = I::Item;
 
@@ -394,16 +568,30 @@ where
                 "{}",
                 serialized
             );
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!("Supersample: {}\n", serialized))));
-            self.monitor.as_ref().and_then(|monitor| {
-                Some(monitor.send(format!(">:{}\n", self.last_val.unwrap_or(-1.0))))
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("Supersample: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(
+                    ">:{}\n",
+                    self.last_val.map_or(-1.0, |sample| sample.value)
+                ));
+            }
+            // the repeated samples are stamped with the time at which they are taken, rounded to
+            // whole microseconds so that rounding errors do not accumulate in the times
+            let step = self.period.unwrap_or(self.step);
+            let repeated = self.last_val.map(|sample| {
+                let time = sample.time + self.count as f64 * step;
+                Sample::new(sample.value, (time * 1e6).round() / 1e6)
             });
             self.count += 1;
-            self.last_val
-        } else if let Some(val) = self.iter.next() {
-            self.last_val = Some(val);
+            repeated
+        } else if let Some(sample) = self.iter.next() {
+            let val = sample.value;
+            if let Some(last) = self.last_val {
+                self.step = (sample.time - last.time).max(0.0) / self.n as f64;
+            }
+            self.last_val = Some(sample);
             self.count = 1;
             let serialized: String = serde_json::to_string(&self).unwrap();
             event!(
@@ -413,13 +601,13 @@ where
                 "{}",
                 serialized
             );
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!("Supersample: {}\n", serialized))));
-            self.monitor
-                .as_ref()
-                .and_then(|monitor| Some(monitor.send(format!(">:{}\n", val))));
-            Some(val)
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("Supersample: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", val));
+            }
+            Some(sample)
         } else {
             None
         }
@@ -428,7 +616,7 @@ where
 
 impl<I> Operation<I, Supersample<I>> for SupersampleParameters
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     fn apply(self, iter: I, monitor: Option<Monitor>) -> Supersample<I> {
         Supersample {
@@ -436,11 +624,21 @@ where
             n: self.n,
             count: 1,
             last_val: None,
+            period: self.period,
+            step: 0.0,
             monitor,
         }
     }
+
+    fn set_period(&mut self, period: f64) {
+        self.period = Some(period);
+    }
 }
 
+register_operation!("supersample", SupersampleParameters);
+
+impl<I> Persistent for Supersample<I> where I: Iterator {}
+
 /// A sub-sampling operation.
 #[derive(Debug, Serialize)]
 pub struct Subsample<I>
@@ -456,7 +654,7 @@ where
 
 impl<I> Iterator for Subsample<I>
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     type Item = I::Item;
 
@@ -474,19 +672,19 @@ where
             "{}",
             serialized
         );
-        self.monitor
-            .as_ref()
-            .and_then(|monitor| Some(monitor.send(format!("Subsample: {}\n", serialized))));
-        self.monitor
-            .as_ref()
-            .and_then(|monitor| Some(monitor.send(format!(">:{}\n", next.unwrap_or(-1.0)))));
+        if let Some(monitor) = &self.monitor {
+            monitor.send(format!("Subsample: {}\n", serialized));
+        }
+        if let Some(monitor) = &self.monitor {
+            monitor.send(format!(">:{}\n", next.map_or(-1.0, |sample| sample.value)));
+        }
         next
     }
 }
 
 impl<I> Operation<I, Subsample<I>> for SubsampleParameters
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     fn apply(self, iter: I, monitor: Option<Monitor>) -> Subsample<I> {
         Subsample {
@@ -497,6 +695,10 @@ where
     }
 }
 
+register_operation!("subsample", SubsampleParameters);
+
+impl<I> Persistent for Subsample<I> where I: Iterator {}
+
 /// A moving average operation.
 #[derive(Debug, Serialize)]
 pub struct Average<I>
@@ -514,13 +716,14 @@ where
 
 impl<I> Iterator for Average<I>
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     type Item = I::Item;
 
     #[inline]
     fn next(&mut self) -> Option<I::Item> {
-        if let Some(val) = self.iter.next() {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
             if self.prev_vals.len() < self.n {
                 self.prev_vals.push(val);
                 let mean = self.prev_vals.iter().sum::<f64>() / (self.prev_vals.len() as f64);
@@ -532,14 +735,14 @@ where
                     "{}",
                     serialized
                 );
-                self.monitor
-                    .as_ref()
-                    .and_then(|monitor| Some(monitor.send(format!("Average: {}\n", serialized))));
-                self.monitor
-                    .as_ref()
-                    .and_then(|monitor| Some(monitor.send(format!(">:{}\n", mean))));
+                if let Some(monitor) = &self.monitor {
+                    monitor.send(format!("Average: {}\n", serialized));
+                }
+                if let Some(monitor) = &self.monitor {
+                    monitor.send(format!(">:{}\n", mean));
+                }
                 debug!("Average: {:2.4}", mean);
-                Some(mean)
+                Some(sample.with_value(mean))
             } else {
                 self.prev_vals[self.index] = val;
                 self.index = (self.index + 1) % self.n;
@@ -552,14 +755,14 @@ where
                     "{}",
                     serialized
                 );
-                self.monitor
-                    .as_ref()
-                    .and_then(|monitor| Some(monitor.send(format!("Average: {}\n", serialized))));
-                self.monitor
-                    .as_ref()
-                    .and_then(|monitor| Some(monitor.send(format!(">:{}\n", mean))));
+                if let Some(monitor) = &self.monitor {
+                    monitor.send(format!("Average: {}\n", serialized));
+                }
+                if let Some(monitor) = &self.monitor {
+                    monitor.send(format!(">:{}\n", mean));
+                }
                 debug!("Average: {:2.4}", mean);
-                Some(mean)
+                Some(sample.with_value(mean))
             }
         } else {
             None
@@ -569,15 +772,483 @@ where
 
 impl<I> Operation<I, Average<I>> for AverageParameters
 where
-    I: Iterator<Item = f64>,
+    I: Iterator<Item = Sample>,
 {
     fn apply(self, iter: I, monitor: Option<Monitor>) -> Average<I> {
         Average {
             iter: iter.fuse(),
-            n: self.n,
+            // an empty window is rejected by validation, but not when built programmatically
+            n: self.n.max(1),
+            index: 0,
+            prev_vals: Vec::new(),
+            monitor,
+        }
+    }
+}
+
+register_operation!("average", AverageParameters);
+
+impl<I> Persistent for Average<I>
+where
+    I: Iterator,
+{
+    fn restore(&mut self, state: &Value) {
+        restore_window(state, self.n, &mut self.index, &mut self.prev_vals);
+    }
+}
+
+/// A moving average operation over a time window.
+#[derive(Debug, Serialize)]
+pub struct TimeAverage<I>
+where
+    I: Iterator,
+{
+    #[serde(skip_serializing)]
+    iter: Fuse<I>,
+    window: f64,
+    prev_samples: VecDeque<Sample>,
+    #[serde(skip_serializing)]
+    monitor: Option<Monitor>,
+}
+
+impl<I> Iterator for TimeAverage<I>
+where
+    I: Iterator<Item = Sample>,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        if let Some(sample) = self.iter.next() {
+            self.prev_samples.push_back(sample);
+            while let Some(oldest) = self.prev_samples.front() {
+                if sample.time - oldest.time > self.window {
+                    self.prev_samples.pop_front();
+                } else {
+                    break;
+                }
+            }
+            let mean = self.prev_samples.iter().map(|s| s.value).sum::<f64>()
+                / (self.prev_samples.len() as f64);
+            let serialized: String = serde_json::to_string(&self).unwrap();
+            event!(
+                Level::TRACE,
+                category = "monitoring",
+                operation = "TimeAverage",
+                "{}",
+                serialized
+            );
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("TimeAverage: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", mean));
+            }
+            debug!("TimeAverage: {:2.4}", mean);
+            Some(sample.with_value(mean))
+        } else {
+            None
+        }
+    }
+}
+
+impl<I> Operation<I, TimeAverage<I>> for TimeAverageParameters
+where
+    I: Iterator<Item = Sample>,
+{
+    fn apply(self, iter: I, monitor: Option<Monitor>) -> TimeAverage<I> {
+        TimeAverage {
+            iter: iter.fuse(),
+            window: self.window,
+            prev_samples: VecDeque::new(),
+            monitor,
+        }
+    }
+}
+
+register_operation!("time_average", TimeAverageParameters);
+
+impl<I> Persistent for TimeAverage<I> where I: Iterator {}
+
+/// A rate limiting operation.
+#[derive(Debug, Serialize)]
+pub struct RateLimit<I>
+where
+    I: Iterator,
+{
+    #[serde(skip_serializing)]
+    iter: Fuse<I>,
+    rate: f64,
+    last: Option<Sample>,
+    #[serde(skip_serializing)]
+    monitor: Option<Monitor>,
+}
+
+impl<I> Iterator for RateLimit<I>
+where
+    I: Iterator<Item = Sample>,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
+            let out = match self.last {
+                Some(last) => {
+                    let max_change = self.rate * (sample.time - last.time);
+                    last.value + (val - last.value).max(-max_change).min(max_change)
+                }
+                None => val,
+            };
+            self.last = Some(sample.with_value(out));
+            let serialized: String = serde_json::to_string(&self).unwrap();
+            event!(
+                Level::TRACE,
+                category = "monitoring",
+                operation = "RateLimit",
+                "{}",
+                serialized
+            );
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("RateLimit: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", out));
+            }
+            Some(sample.with_value(out))
+        } else {
+            None
+        }
+    }
+}
+
+impl<I> Operation<I, RateLimit<I>> for RateLimitParameters
+where
+    I: Iterator<Item = Sample>,
+{
+    fn apply(self, iter: I, monitor: Option<Monitor>) -> RateLimit<I> {
+        RateLimit {
+            iter: iter.fuse(),
+            rate: self.rate,
+            last: None,
+            monitor,
+        }
+    }
+}
+
+register_operation!("rate_limit", RateLimitParameters);
+
+impl<I> Persistent for RateLimit<I> where I: Iterator {}
+
+/// Median of a slice of values; `None` if the slice is empty.
+fn median(vals: &[f64]) -> Option<f64> {
+    if vals.is_empty() {
+        return None;
+    }
+    let mut sorted = vals.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        Some(sorted[mid])
+    } else {
+        Some((sorted[mid - 1] + sorted[mid]) / 2.0)
+    }
+}
+
+/// A moving median operation.
+#[derive(Debug, Serialize)]
+pub struct Median<I>
+where
+    I: Iterator,
+{
+    #[serde(skip_serializing)]
+    iter: Fuse<I>,
+    n: usize,
+    index: usize,
+    prev_vals: Vec<f64>,
+    #[serde(skip_serializing)]
+    monitor: Option<Monitor>,
+}
+
+impl<I> Iterator for Median<I>
+where
+    I: Iterator<Item = Sample>,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
+            if self.prev_vals.len() < self.n {
+                self.prev_vals.push(val);
+            } else {
+                self.prev_vals[self.index] = val;
+                self.index = (self.index + 1) % self.n;
+            }
+            let out = median(&self.prev_vals).unwrap_or(val);
+            let serialized: String = serde_json::to_string(&self).unwrap();
+            event!(
+                Level::TRACE,
+                category = "monitoring",
+                operation = "Median",
+                "{}",
+                serialized
+            );
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("Median: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", out));
+            }
+            debug!("Median: {:2.4}", out);
+            Some(sample.with_value(out))
+        } else {
+            None
+        }
+    }
+}
+
+impl<I> Operation<I, Median<I>> for MedianParameters
+where
+    I: Iterator<Item = Sample>,
+{
+    fn apply(self, iter: I, monitor: Option<Monitor>) -> Median<I> {
+        Median {
+            iter: iter.fuse(),
+            // an empty window is rejected by validation, but not when built programmatically
+            n: self.n.max(1),
             index: 0,
             prev_vals: Vec::new(),
             monitor,
         }
     }
 }
+
+register_operation!("median", MedianParameters);
+
+impl<I> Persistent for Median<I>
+where
+    I: Iterator,
+{
+    fn restore(&mut self, state: &Value) {
+        restore_window(state, self.n, &mut self.index, &mut self.prev_vals);
+    }
+}
+
+/// An outlier rejecting operation (a Hampel filter, optionally combined with a limit on the jump
+/// between consecutive outputs). Rejected samples are replaced by the median of the window, so the
+/// timing of the pipeline is not affected. Rejected samples are still kept in the window: a
+/// genuine step in the input is let through once it makes up the majority of the window.
+#[derive(Debug, Serialize)]
+pub struct RejectOutliers<I>
+where
+    I: Iterator,
+{
+    #[serde(skip_serializing)]
+    iter: Fuse<I>,
+    n: usize,
+    k: Option<f64>,
+    max_jump: Option<f64>,
+    index: usize,
+    prev_vals: Vec<f64>,
+    last_out: Option<f64>,
+    rejected: u64,
+    #[serde(skip_serializing)]
+    monitor: Option<Monitor>,
+}
+
+impl<I> RejectOutliers<I>
+where
+    I: Iterator,
+{
+    /// Scale factor that makes the MAD a consistent estimator of the standard deviation for
+    /// normally distributed data.
+    const MAD_SCALE: f64 = 1.4826;
+
+    fn is_outlier(&self, val: f64, med: f64) -> bool {
+        let too_far = self.k.is_some_and(|k| {
+            let deviations: Vec<f64> = self.prev_vals.iter().map(|x| (x - med).abs()).collect();
+            let mad = median(&deviations).unwrap_or(0.0);
+            (val - med).abs() > k * Self::MAD_SCALE * mad
+        });
+        let too_steep = match (self.max_jump, self.last_out) {
+            (Some(max_jump), Some(last)) => (val - last).abs() > max_jump,
+            _ => false,
+        };
+        too_far || too_steep
+    }
+}
+
+impl<I> Iterator for RejectOutliers<I>
+where
+    I: Iterator<Item = Sample>,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
+            if self.prev_vals.len() < self.n {
+                self.prev_vals.push(val);
+            } else {
+                self.prev_vals[self.index] = val;
+                self.index = (self.index + 1) % self.n;
+            }
+            let med = median(&self.prev_vals).unwrap_or(val);
+            let out = if self.is_outlier(val, med) {
+                debug!(
+                    "RejectOutliers: rejected {:2.4}, replaced by {:2.4}",
+                    val, med
+                );
+                self.rejected += 1;
+                med
+            } else {
+                val
+            };
+            self.last_out = Some(out);
+            let serialized: String = serde_json::to_string(&self).unwrap();
+            event!(
+                Level::TRACE,
+                category = "monitoring",
+                operation = "RejectOutliers",
+                "{}",
+                serialized
+            );
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("RejectOutliers: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", out));
+            }
+            Some(sample.with_value(out))
+        } else {
+            None
+        }
+    }
+}
+
+impl<I> Operation<I, RejectOutliers<I>> for RejectOutliersParameters
+where
+    I: Iterator<Item = Sample>,
+{
+    fn apply(self, iter: I, monitor: Option<Monitor>) -> RejectOutliers<I> {
+        RejectOutliers {
+            iter: iter.fuse(),
+            // an empty window is rejected by validation, but not when built programmatically
+            n: self.n.max(1),
+            k: self.k,
+            max_jump: self.max_jump,
+            index: 0,
+            prev_vals: Vec::new(),
+            last_out: None,
+            rejected: 0,
+            monitor,
+        }
+    }
+}
+
+register_operation!("reject_outliers", RejectOutliersParameters);
+
+impl<I> Persistent for RejectOutliers<I>
+where
+    I: Iterator,
+{
+    fn restore(&mut self, state: &Value) {
+        restore_window(state, self.n, &mut self.index, &mut self.prev_vals);
+        restore_field(state, "last_out", &mut self.last_out);
+        restore_field(state, "rejected", &mut self.rejected);
+    }
+}
+
+/// An on-off operation: the output switches to `high` when the input rises above `setpoint +
+/// hysteresis` and back to `low` when it falls below `setpoint - hysteresis` (the other way round
+/// with direct action). It starts as if the input were below the setpoint.
+#[derive(Debug, Serialize)]
+pub struct Relay<I>
+where
+    I: Iterator,
+{
+    #[serde(skip_serializing)]
+    iter: Fuse<I>,
+    setpoint: f64,
+    low: f64,
+    high: f64,
+    hysteresis: f64,
+    direction: Direction,
+    /// Whether the input was last beyond the setpoint on the high side
+    above: bool,
+    #[serde(skip_serializing)]
+    monitor: Option<Monitor>,
+}
+
+impl<I> Iterator for Relay<I>
+where
+    I: Iterator<Item = Sample>,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        if let Some(sample) = self.iter.next() {
+            let val = sample.value;
+            if val > self.setpoint + self.hysteresis {
+                self.above = true;
+            } else if val < self.setpoint - self.hysteresis {
+                self.above = false;
+            }
+            let out = match (self.direction, self.above) {
+                (Direction::Reverse, true) | (Direction::Direct, false) => self.high,
+                _ => self.low,
+            };
+            let serialized: String = serde_json::to_string(&self).unwrap();
+            event!(
+                Level::TRACE,
+                category = "monitoring",
+                operation = "Relay",
+                "{}",
+                serialized
+            );
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!("Relay: {}\n", serialized));
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.send(format!(">:{}\n", out));
+            }
+            Some(sample.with_value(out))
+        } else {
+            None
+        }
+    }
+}
+
+impl<I> Operation<I, Relay<I>> for RelayParameters
+where
+    I: Iterator<Item = Sample>,
+{
+    fn apply(self, iter: I, monitor: Option<Monitor>) -> Relay<I> {
+        Relay {
+            iter: iter.fuse(),
+            setpoint: self.setpoint,
+            low: self.low,
+            high: self.high,
+            hysteresis: self.hysteresis,
+            direction: self.direction,
+            above: false,
+            monitor,
+        }
+    }
+}
+
+register_operation!("relay", RelayParameters);
+
+impl<I> Persistent for Relay<I>
+where
+    I: Iterator,
+{
+    fn restore(&mut self, state: &Value) {
+        restore_field(state, "above", &mut self.above);
+    }
+}
@@ -2,15 +2,24 @@
 //! definition of a pipeline. The trait [Operation] allows the creation at runtime of the operation
 //! itself as an iterator adaptor.
 
+use crate::sample::Sample;
+use crate::state::StateSlot;
+use crate::validation::{Checks, Validate, ValidationError};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Metadata, Schema, SchemaObject, SubschemaValidation};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use pid::Pid;
 
 use std::sync::mpsc::Sender;
 
+/// Channel through which an operation reports its internal state; every message is prefixed with
+/// the `id` of the operation (its index in the pipeline, itself prefixed by the name of the
+/// pipeline if it has one).
 #[derive(Debug)]
 pub struct Monitor {
-    pub id: usize,
+    pub id: String,
     pub tx: Sender<String>,
 }
 
@@ -32,86 +41,561 @@ where
     /// Given self and an input iterator; produce a new iterator that applies the operation
     /// described by `self`.
     fn apply(self, iter: I, monitor: Option<Monitor>) -> J;
+
+    /// Tell the operation that a value is taken from it every `period` seconds, before it is
+    /// applied; only the operations which need it (such as a supersample) keep it.
+    fn set_period(&mut self, _period: f64) {}
+}
+
+/// The iterator type flowing from one operation of a pipeline to the next.
+pub type BoxedIterator = Box<dyn Iterator<Item = Sample> + Send>;
+
+/// Object safe version of [Operation], implemented by every operation registered with
+/// [register_operation][crate::register_operation]. This is what allows a pipeline to be built at
+/// runtime from a list of `Box<dyn OperationParameters>`, which (de-)serialize as
+/// `{"type": "operation_name", ...parameters...}`.
+#[typetag::serde(tag = "type")]
+pub trait OperationParameters: Send {
+    /// Given self and a boxed input iterator, produce a boxed iterator that applies the operation
+    /// described by `self`. If a state slot is given, the operation starts from the state it holds
+    /// (if any) and keeps its latest state in it (see [crate::state]).
+    fn build(
+        self: Box<Self>,
+        iter: BoxedIterator,
+        monitor: Option<Monitor>,
+        state: Option<StateSlot>,
+    ) -> BoxedIterator;
+
+    /// See [Operation::set_period].
+    fn set_period(&mut self, period: f64);
+
+    /// Problems with the parameters (see [Validate]), with paths relative to the parameters.
+    fn validate(&self) -> Vec<ValidationError>;
+}
+
+/// How many values `operations` take from their input per value they produce, as a fraction.
+pub fn values_taken(operations: &[Box<dyn OperationParameters>]) -> (u64, u64) {
+    operations.iter().fold((1, 1), |(num, den), operation| {
+        let value = serde_json::to_value(operation).unwrap_or_default();
+        let n = value["n"].as_u64().unwrap_or(1);
+        match value["type"].as_str() {
+            Some("supersample") => reduce_fraction(num, den * n.max(1)),
+            Some("subsample") => reduce_fraction(num * (n + 1), den),
+            _ => (num, den),
+        }
+    })
+}
+
+/// `num / den` in lowest terms.
+pub fn reduce_fraction(num: u64, den: u64) -> (u64, u64) {
+    let (mut a, mut b) = (num, den);
+    while b != 0 {
+        let remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+    (num / a, den / a)
 }
 
-/// Union type to store the description of some operation; this way we can easily
-/// serialize/deserialize operations into a single array.
-// TODO Is it possible to create a macro that defines this Union?  Turns out yes! Check out typetag
-// crate!
-#[derive(Serialize, Deserialize)]
-pub enum OperationParameters {
-    // OperationName: OperationParams
-    Identity(IdentityParameters),
-    PID(PIDParameters),
-    DampenedOscillator(DampenedOscillatorParameters),
-    Clip(ClipParameters),
-    AtLeast(AtLeastParameters),
-    Supersample(SupersampleParameters),
-    Subsample(SubsampleParameters),
-    Average(AverageParameters),
+/// An operation registered with [register_operation][crate::register_operation], as needed to
+/// describe it in the JSON schema of configurations.
+pub struct RegisteredOperation {
+    pub name: &'static str,
+    /// The schema of the parameters of the operation (without their `type`)
+    pub schema: fn(&mut SchemaGenerator) -> Schema,
+}
+
+inventory::collect!(RegisteredOperation);
+
+/// An operation is described by any of the registered operations, together with its `type`.
+impl JsonSchema for dyn OperationParameters {
+    fn schema_name() -> String {
+        "Operation".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut operations: Vec<&RegisteredOperation> =
+            inventory::iter::<RegisteredOperation>.into_iter().collect();
+        operations.sort_by_key(|operation| operation.name);
+        let variants = operations
+            .into_iter()
+            .map(|operation| {
+                let mut schema = (operation.schema)(gen).into_object();
+                schema.instance_type = Some(InstanceType::Object.into());
+                let tag = SchemaObject {
+                    const_value: Some(operation.name.into()),
+                    ..Default::default()
+                };
+                let object = schema.object();
+                object.properties.insert("type".to_string(), tag.into());
+                object.required.insert("type".to_string());
+                schema.into()
+            })
+            .collect();
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "An operation of a pipeline; its `type` selects which one".to_string(),
+                ),
+                ..Default::default()
+            })),
+            subschemas: Some(Box::new(SubschemaValidation {
+                one_of: Some(variants),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// Register an operation under a name: `register_operation!("name", NameParameters)`, where
+/// `NameParameters` implements `Serialize`, `Deserialize`, `JsonSchema`, [Validate] and
+/// [Operation] for a [BoxedIterator] input, and the operation itself implements
+/// [Persistent][crate::state::Persistent]. After this, the operation can be used in the
+/// configuration of a pipeline as `{"type": "name", ...}`, and appears in its JSON schema. This
+/// also works from other crates (which then need to depend on `typetag` and `schemars` as well).
+#[macro_export]
+macro_rules! register_operation {
+    ($name:literal, $params:ty) => {
+        #[typetag::serde(name = $name)]
+        impl $crate::operations::parameters::OperationParameters for $params {
+            fn build(
+                self: Box<Self>,
+                iter: $crate::operations::parameters::BoxedIterator,
+                monitor: Option<$crate::operations::parameters::Monitor>,
+                state: Option<$crate::state::StateSlot>,
+            ) -> $crate::operations::parameters::BoxedIterator {
+                let operation =
+                    $crate::operations::parameters::Operation::apply(*self, iter, monitor);
+                $crate::state::persist(operation, state)
+            }
+
+            fn set_period(&mut self, period: f64) {
+                <$params as $crate::operations::parameters::Operation<
+                    $crate::operations::parameters::BoxedIterator,
+                    _,
+                >>::set_period(self, period)
+            }
+
+            fn validate(&self) -> Vec<$crate::validation::ValidationError> {
+                $crate::validation::Validate::validate(self)
+            }
+        }
+
+        $crate::inventory::submit! {
+            $crate::operations::parameters::RegisteredOperation {
+                name: $name,
+                schema: <$params as $crate::schemars::JsonSchema>::json_schema,
+            }
+        }
+    };
 }
 
 /// An operation which just reproduces the input iterator (mostly for testing purposes; no real use
 /// case)
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct IdentityParameters;
 
-/// An operation that implements a PID control
-#[derive(Serialize, Deserialize, Clone, Copy)]
+/// An operation that implements a PID control for a reverse acting loop with percentage output:
+/// only the negative part of each of the P, I and D terms is kept, and the output is clamped to
+/// `offset + [0, 100]`. See [SignedPIDParameters] for a general PID control.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct PIDParameters {
-    /// PID parameters
-    pub pid: Pid<f64>,
+    /// Proportional gain
+    pub kp: f64,
+    /// Integral gain
+    pub ki: f64,
+    /// Derivative gain
+    pub kd: f64,
+    /// Limit on the absolute value of the proportional term (100 by default)
+    #[serde(default = "default_limit")]
+    pub p_limit: f64,
+    /// Limit on the absolute value of the integral term (100 by default)
+    #[serde(default = "default_limit")]
+    pub i_limit: f64,
+    /// Limit on the absolute value of the derivative term (100 by default)
+    #[serde(default = "default_limit")]
+    pub d_limit: f64,
+    /// Target value of the input
+    pub setpoint: f64,
     /// Value to add to PID output
+    #[serde(default)]
     pub offset: u32,
 }
 
-/// An operation which uses a critcially dampened oscillator to reach a target value
-#[derive(Serialize, Deserialize, Clone, Copy)]
+impl PIDParameters {
+    /// The controller (from the [pid] crate) described by these parameters.
+    pub fn pid(&self) -> Pid<f64> {
+        Pid::new(
+            self.kp,
+            self.ki,
+            self.kd,
+            self.p_limit,
+            self.i_limit,
+            self.d_limit,
+            self.setpoint,
+        )
+    }
+}
+
+fn default_limit() -> f64 {
+    100.0
+}
+
+/// Direction of action of a controller (`"reverse"` by default).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Output increases when the input is below the setpoint (e.g. heating)
+    Direct,
+    /// Output increases when the input is above the setpoint (e.g. cooling)
+    #[default]
+    Reverse,
+}
+
+/// Anti-windup strategy of a PID control; it prevents the integral term from growing while the
+/// output is saturated at `output_min` or `output_max` (`"clamping"` by default).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AntiWindup {
+    /// No anti-windup (other than `i_limit`, if given)
+    Off,
+    /// Stop integrating while the output is saturated and the error drives it further into
+    /// saturation
+    #[default]
+    Clamping,
+    /// Feed the difference between the clamped and unclamped output back into the integral term
+    BackCalculation {
+        /// Gain of the feedback (typically between `ki / kp` and `1`)
+        tracking_gain: f64,
+    },
+}
+
+/// An operation that implements a PID control whose output is the full signed sum of the P, I
+/// and D terms plus a bias, clamped to `[output_min, output_max]`. The derivative term acts on
+/// the measurement (not on the error), so changes of setpoint do not produce spikes.
+///
+/// The setpoint and gains are fixed while the control loop runs; to change them, reload the
+/// configuration: the new control carries on from the state of the previous one (see
+/// [PidController::resume_from][crate::operations::controller::PidController::resume_from]), so
+/// that its output does not jump.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+pub struct SignedPIDParameters {
+    /// Proportional gain
+    pub kp: f64,
+    /// Integral gain
+    pub ki: f64,
+    /// Derivative gain
+    pub kd: f64,
+    /// Target value of the input
+    pub setpoint: f64,
+    /// Direction of action of the controller
+    #[serde(default)]
+    pub direction: Direction,
+    /// Value to add to PID output (0 by default)
+    #[serde(default)]
+    pub bias: f64,
+    /// Minimum value of output (0 by default)
+    #[serde(default)]
+    pub output_min: f64,
+    /// Maximum value of output (100 by default)
+    #[serde(default = "default_limit")]
+    pub output_max: f64,
+    /// Limit on the absolute value of the integral term
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub i_limit: Option<f64>,
+    /// Anti-windup strategy
+    #[serde(default)]
+    pub anti_windup: AntiWindup,
+    /// Time constant of the low-pass filter applied to the derivative term (in seconds, or in units
+    /// of `dt` if it is given)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derivative_filter: Option<f64>,
+    /// Integration time step; if not given, the time elapsed between samples (in seconds) is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dt: Option<f64>,
+}
+
+impl SignedPIDParameters {
+    /// A PID control with percentage output (i.e. between 0 and 100), no bias, clamping
+    /// anti-windup and an unfiltered derivative term, which integrates in seconds.
+    pub fn new(kp: f64, ki: f64, kd: f64, setpoint: f64, direction: Direction) -> Self {
+        SignedPIDParameters {
+            kp,
+            ki,
+            kd,
+            setpoint,
+            direction,
+            bias: 0.0,
+            output_min: 0.0,
+            output_max: default_limit(),
+            i_limit: None,
+            anti_windup: AntiWindup::default(),
+            derivative_filter: None,
+            dt: None,
+        }
+    }
+}
+
+/// An operation which uses a critcially dampened oscillator to follow its input; it starts at 100
+/// (i.e. a fan starts at full speed).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct DampenedOscillatorParameters {
     /// Mass of particle
     pub m: f64,
     /// Spring constant
     pub k: f64,
-    /// Integration time step
-    pub dt: f64,
-    /// Initial target (not very important)
-    pub target: f64,
+    /// Integration time step; if not given, the time elapsed between samples (in seconds) is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dt: Option<f64>,
 }
 
 /// An operation that clips all values
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct ClipParameters {
-    /// Minimum value of output
+    /// Minimum value of output (0 by default)
+    #[serde(default)]
     pub min: f64,
-    /// Maximum value of output
+    /// Maximum value of output (100 by default)
+    #[serde(default = "default_limit")]
     pub max: f64,
 }
 
 /// An operation that clamp values below the reference
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct AtLeastParameters {
     /// Reference value
     pub val: f64,
 }
 
 /// An operation that supersamples its input
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct SupersampleParameters {
     /// How many times to supersample (i.e. it repeats it's input n times before checking for a new
-    /// input)
+    /// input); the repeated samples are stamped with the time at which they are taken, so that the
+    /// operations after it see time pass
     pub n: usize,
+    /// Time between two values taken from the supersample, in seconds, which the repeated samples
+    /// are spaced by; set by the pipeline from its sample rate (if not, the time between the last
+    /// two values of the input, divided by `n`, is used)
+    #[serde(skip)]
+    pub period: Option<f64>,
 }
 
 /// An operation that subsamples its input
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct SubsampleParameters {
     /// How many times to subsample (i.e. it drops it's input n times before providing a new output
     pub n: usize,
 }
 
 /// An operation that averages its input (running average)
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
 pub struct AverageParameters {
     /// How many values to average (i.e. size of window for running average)
     pub n: usize,
 }
+
+/// An operation that averages its input over a time window (running average)
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+pub struct TimeAverageParameters {
+    /// Length (in seconds) of the window for running average
+    pub window: f64,
+}
+
+/// An operation that limits how fast its output can change
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+pub struct RateLimitParameters {
+    /// Maximum change of the output per second (in either direction)
+    pub rate: f64,
+}
+
+/// An operation that takes the median of its input (running median)
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+pub struct MedianParameters {
+    /// How many values to take the median of (i.e. size of window for running median)
+    pub n: usize,
+}
+
+/// An operation that replaces outliers (e.g. single-sample spikes) by the median of the recent
+/// window. A sample is an outlier if it deviates from the median of the window by more than `k`
+/// times the median absolute deviation (MAD) of the window, or if it jumps by more than `max_jump`
+/// from the previous output. At least one of the two criteria should be given, otherwise no sample
+/// is ever rejected.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+pub struct RejectOutliersParameters {
+    /// Size of the window of recent values (including the current one)
+    pub n: usize,
+    /// Maximum allowed deviation from the median, in multiples of the (scaled) MAD
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<f64>,
+    /// Maximum allowed absolute change with respect to the previous output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_jump: Option<f64>,
+}
+
+/// An operation that switches its output between two values depending on which side of the
+/// setpoint its input is (an on-off control), e.g. for the relay experiment of autotuning (see
+/// [tuning][crate::tuning]).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+pub struct RelayParameters {
+    /// Value of the input around which the output switches
+    pub setpoint: f64,
+    /// Output while the input is on the low side of the setpoint (with reverse action)
+    #[serde(default)]
+    pub low: f64,
+    /// Output while the input is on the high side of the setpoint (with reverse action)
+    #[serde(default = "default_limit")]
+    pub high: f64,
+    /// Distance from the setpoint the input must cross for the output to switch (0 by default)
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Direction of action; with direct action the output is `high` below the setpoint
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+impl Validate for IdentityParameters {}
+
+impl Validate for PIDParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .finite("kp", self.kp)
+            .finite("ki", self.ki)
+            .finite("kd", self.kd)
+            .finite("setpoint", self.setpoint)
+            .non_negative("p_limit", self.p_limit)
+            .non_negative("i_limit", self.i_limit)
+            .non_negative("d_limit", self.d_limit);
+        checks.into_errors()
+    }
+}
+
+impl Validate for SignedPIDParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .finite("kp", self.kp)
+            .finite("ki", self.ki)
+            .finite("kd", self.kd)
+            .finite("setpoint", self.setpoint)
+            .finite("bias", self.bias)
+            .finite("output_min", self.output_min)
+            .finite("output_max", self.output_max)
+            .ordered("output_max", self.output_min, self.output_max);
+        if let Some(i_limit) = self.i_limit {
+            checks.non_negative("i_limit", i_limit);
+        }
+        if let AntiWindup::BackCalculation { tracking_gain } = self.anti_windup {
+            checks.non_negative("anti_windup.back_calculation.tracking_gain", tracking_gain);
+        }
+        if let Some(time_constant) = self.derivative_filter {
+            checks.non_negative("derivative_filter", time_constant);
+        }
+        if let Some(dt) = self.dt {
+            checks.positive("dt", dt);
+        }
+        checks.into_errors()
+    }
+}
+
+impl Validate for DampenedOscillatorParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.positive("m", self.m).positive("k", self.k);
+        if let Some(dt) = self.dt {
+            checks.positive("dt", dt);
+        }
+        checks.into_errors()
+    }
+}
+
+impl Validate for ClipParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .finite("min", self.min)
+            .finite("max", self.max)
+            .ordered("max", self.min, self.max);
+        checks.into_errors()
+    }
+}
+
+impl Validate for AtLeastParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.finite("val", self.val);
+        checks.into_errors()
+    }
+}
+
+impl Validate for SupersampleParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.require(self.n > 0, "n", "must be at least 1");
+        checks.into_errors()
+    }
+}
+
+/// Any number of values can be dropped, including none.
+impl Validate for SubsampleParameters {}
+
+impl Validate for AverageParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.require(self.n > 0, "n", "must be at least 1");
+        checks.into_errors()
+    }
+}
+
+impl Validate for TimeAverageParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.positive("window", self.window);
+        checks.into_errors()
+    }
+}
+
+impl Validate for RateLimitParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.positive("rate", self.rate);
+        checks.into_errors()
+    }
+}
+
+impl Validate for MedianParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.require(self.n > 0, "n", "must be at least 1");
+        checks.into_errors()
+    }
+}
+
+impl Validate for RejectOutliersParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.require(self.n > 0, "n", "must be at least 1");
+        if let Some(k) = self.k {
+            checks.positive("k", k);
+        }
+        if let Some(max_jump) = self.max_jump {
+            checks.positive("max_jump", max_jump);
+        }
+        checks.into_errors()
+    }
+}
+
+impl Validate for RelayParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .finite("setpoint", self.setpoint)
+            .finite("low", self.low)
+            .finite("high", self.high)
+            .non_negative("hysteresis", self.hysteresis);
+        checks.into_errors()
+    }
+}
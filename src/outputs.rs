@@ -1,45 +1,81 @@
+use crate::inputs::find_command;
+use crate::simulation::{Fans, SimulatedFan};
 use ::std::{thread, time};
 use log::debug;
 use rppal::pwm;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::process::Command;
 
 /// The output that is being controlled. They implement the `Pushable` trait, meaning that they
 /// define a way to send (i.e. push) a value to the output.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub enum Output {
+    /// Hardware PWM of a Raspberry Pi (channel 0), with the value as duty cycle in percent
     PWM,
+    /// Command run with the value as argument whenever it changes
     External(String),
+    /// Fan of the simulated plant with the given name (see [simulation][crate::simulation])
+    Simulated(String),
+    /// Values are dropped (e.g. to try out a pipeline without acting on anything)
+    Discard,
 }
 
 pub trait Pushable {
     fn push(&mut self, val: f64);
 }
 
-/// Start the control loop with no exit condition. This takes essentially any iterator which
-/// produces `f64`s, which is sampled at a given `rate`, and these values are then fed into the
-/// output. NOTE: The current implementation *will not push new values unless the differ by more
-/// than 0.001*. This is, of course, very arbitrary and has to change in future versions, possibly
-/// providing an adjustable threshold.
-pub fn sample_forever(
-    mut source: Box<dyn Iterator<Item = f64>>,
-    mut output: Box<dyn Pushable>,
-    rate: u64,
-) {
-    let mut last: f64 = 0.0;
-    loop {
-        let next: f64 = match source.next() {
-            Some(val) => val,
-            None => break,
-        };
-        if (last * 100.).round() as u64 != (next * 100.).round() as u64 {
-            output.push(next);
+impl<P> Pushable for Box<P>
+where
+    P: Pushable + ?Sized,
+{
+    fn push(&mut self, val: f64) {
+        (**self).push(val)
+    }
+}
+
+impl Output {
+    /// Create the output described by `self` (a simulated fan is connected to nothing, see
+    /// [open_with][Output::open_with]).
+    pub fn open(self) -> Box<dyn Pushable + Send> {
+        self.open_with(&Fans::default())
+    }
+
+    /// Create the output described by `self`; a simulated fan is found among `fans`.
+    pub fn open_with(self, fans: &Fans) -> Box<dyn Pushable + Send> {
+        // TODO: How to deal with errors?
+        match self {
+            Output::PWM => Box::new(PWM::new().unwrap()),
+            Output::External(cmd) => Box::new(External { cmd }),
+            Output::Simulated(name) => Box::new(SimulatedFan::new(&name, fans)),
+            Output::Discard => Box::new(Discard),
+        }
+    }
+
+    /// The output to use instead of this one when simulating: simulated fans are kept, anything
+    /// else is discarded, so that nothing real is acted upon.
+    pub fn simulated(self) -> Output {
+        match self {
+            Output::Simulated(name) => Output::Simulated(name),
+            _ => Output::Discard,
+        }
+    }
+
+    /// Check that the device or command this output writes to is available.
+    pub fn check_device(&self) -> Result<(), String> {
+        match self {
+            Output::PWM => std::fs::metadata(PWM_CHIP)
+                .map(|_| ())
+                .map_err(|err| format!("cannot find PWM chip {}: {}", PWM_CHIP, err)),
+            Output::External(cmd) => find_command(cmd),
+            Output::Simulated(_) | Output::Discard => Ok(()),
         }
-        last = next;
-        thread::sleep(time::Duration::from_millis(rate));
     }
 }
 
+/// Sysfs directory of the PWM chip used by [PWM].
+const PWM_CHIP: &str = "/sys/class/pwm/pwmchip0";
+
 /// Wrapper around (rppal)[https://crates.io/crates/rppal]'s pwm; it has a fixed frequency in
 /// current implementation (see (new)[#method.new]) and also has a special logic if it is starting
 /// from a duty cycle of 0: it turns the output to 100% and blocks for 500ms, and then returns to
@@ -103,3 +139,10 @@ impl Pushable for External {
             .expect("External output command failed");
     }
 }
+
+/// Output which drops every value (see [Output::Discard]).
+pub struct Discard;
+
+impl Pushable for Discard {
+    fn push(&mut self, _val: f64) {}
+}
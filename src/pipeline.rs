@@ -1,8 +1,27 @@
-use crate::inputs::Input;
+use crate::alarms::{
+    AlertRule, Alerts, Condition, InputErrorParameters, InputFailure, OverTemperature,
+    OverTemperatureParameters, StageValues, StallDetector, StallParameters, Supervisor,
+};
+use crate::clock::{SharedClock, SystemClock};
+use crate::graph::{self, GraphError, Node};
+use crate::inputs::{Input, Timestamped};
 use crate::operations::parameters::*;
-use crate::outputs::{sample_forever, External, Output, PWM};
+use crate::outputs::{Output, Pushable};
+use crate::recording::Recorder;
+use crate::sample::Sample;
+use crate::scheduler::{Scheduler, SchedulerStats};
+use crate::simulation::Fans;
+use crate::state::StateStore;
+use crate::tachometer::Tachometer;
+use crate::validation::{Validate, ValidationError};
+use log::{error, info};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 /// A pipeline is nothing more than a runtime-defined series of iterator transformers. That is,
 /// starting from an [Input] (an iterator), it creates on the heap a series of
@@ -12,74 +31,1099 @@ use std::sync::mpsc;
 /// produce this on the heap but rather using the same approach as in the iterator transformers of
 /// the standard library, but in that way the construction of the pipeline at runtime (e.g. from a
 /// config file) is prevented.
-#[derive(Serialize, Deserialize)]
+///
+/// Instead of a single input, a pipeline can also take its values from a graph of named
+/// [nodes][Node] (see [graph]); its operations then apply to the output of node `from`.
+///
+/// This struct is the (de-)serializable description of a pipeline; to build a pipeline
+/// programmatically see [Pipeline::builder].
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Pipeline {
-    pub input: Input,
-    pub operations: Vec<OperationParameters>,
+    /// Source of values (unless `nodes` are given)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<Input>,
+    /// Named nodes of a graph of operations (instead of `input`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub nodes: BTreeMap<String, Node>,
+    /// Name of the node whose output the operations apply to (with `nodes`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// Operations applied in order to the values of the source
+    #[serde(default)]
+    pub operations: Vec<Box<dyn OperationParameters>>,
+    /// Destination of the values produced by the last operation
     pub output: Output,
+    /// Period of the control loop, in milliseconds (1000 by default)
+    #[serde(default = "default_sample_rate")]
     pub sample_rate: u64,
+    /// Tachometer of the fan driven by this pipeline; its speed is read after every sample and
+    /// reported along with the state of the loop
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tachometer: Option<Box<Tachometer>>,
+    /// Raise an alarm when the fan does not spin although it should (requires a `tachometer`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stall: Option<StallParameters>,
+    /// Emergency actions taken when the source stays above a critical value despite the fan
+    /// running at full speed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub over_temperature: Option<OverTemperatureParameters>,
+    /// How the loop carries on when its `input` cannot be read; by default it stops at the first
+    /// failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_input_error: Option<InputErrorParameters>,
+    /// Alarms raised when the values of a stage meet a condition for some time
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alerts: Vec<AlertRule>,
+}
+
+fn default_sample_rate() -> u64 {
+    1000
 }
 
 impl Pipeline {
-    /// Given a Pipeline, it starts a control loop that polls its input and pushes the processed
-    /// values to the output (see (`sample_forever`)[sample_forever]). Current
-    /// implementation is quite unintuitive: if no monitoring (`monitored == false`), it blocks
-    /// forever by calling `sample_forever` on the current thread; but if monitoring is requested,
-    /// it starts a new thread to execute the control loop and returns a channel to access internal
-    /// state of the control loop.
-    pub fn start(self, monitored: bool) -> Option<mpsc::Receiver<String>> {
-        let sample_rate = self.sample_rate;
-        let mut last_iterator: Box<dyn Iterator<Item = f64> + Send> = Box::new(self.input);
-        let (tx, rx) = mpsc::channel();
-        for (index, operation) in self.operations.iter().enumerate() {
-            let local_tx = if monitored {
-                Some(Monitor {
-                    id: index,
-                    tx: tx.clone(),
-                })
-            } else {
-                None
+    /// Start building a pipeline programmatically, e.g.:
+    ///
+    /// ```no_run
+    /// # use pifan::pipeline::Pipeline;
+    /// # use pifan::inputs::Input;
+    /// # use pifan::outputs::Output;
+    /// # use pifan::operations::parameters::*;
+    /// let handle = Pipeline::builder()
+    ///     .input(Input::RPiCpuTemp)
+    ///     .then(AverageParameters { n: 5 })
+    ///     .then(SignedPIDParameters::new(2.0, 0.5, 1.0, 45.0, Direction::Reverse))
+    ///     .output(Output::PWM.open())
+    ///     .sample_rate(1000)
+    ///     .build()
+    ///     .unwrap()
+    ///     .spawn();
+    /// ```
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    /// A builder initialised with the input (or nodes), operations, output and sample rate of
+    /// this pipeline; use it to configure monitoring (see [PipelineBuilder::monitored]).
+    pub fn into_builder(self) -> PipelineBuilder {
+        self.into_builder_with_clock(SystemClock::shared())
+    }
+
+    /// Same as [into_builder][Pipeline::into_builder], but the pipeline (including its simulated
+    /// inputs) takes the time from `clock` (see [PipelineBuilder::clock]).
+    pub fn into_builder_with_clock(self, clock: SharedClock) -> PipelineBuilder {
+        self.into_builder_with(clock, &Fans::default())
+    }
+
+    /// Same as [into_builder_with_clock][Pipeline::into_builder_with_clock], and the simulated
+    /// plants, outputs and tachometers of the pipeline are connected to `fans` (e.g. to share
+    /// them with other pipelines of the same simulation).
+    pub fn into_builder_with(self, clock: SharedClock, fans: &Fans) -> PipelineBuilder {
+        let mut builder = Pipeline::builder()
+            .output(self.output.open_with(fans))
+            .sample_rate(self.sample_rate);
+        if let Some(mut input) = self.input {
+            input.set_clock(&clock);
+            input.set_fans(fans);
+            builder = builder.fallible_input(input.readings());
+        }
+        for (name, mut node) in self.nodes {
+            if let Node::Input(input) = &mut node {
+                input.set_fans(fans);
+            }
+            builder = builder.node(name, node);
+        }
+        if let Some(from) = self.from {
+            builder = builder.from(from);
+        }
+        for operation in self.operations {
+            builder = builder.then_boxed(operation);
+        }
+        if let Some(mut tachometer) = self.tachometer {
+            tachometer.set_fans(fans);
+            builder = builder.tachometer(*tachometer);
+        }
+        if let Some(stall) = self.stall {
+            builder = builder.supervised_by(StallDetector::new(stall));
+        }
+        if let Some(over_temperature) = self.over_temperature {
+            builder = builder.supervised_by(OverTemperature::new(over_temperature));
+        }
+        if let Some(on_input_error) = self.on_input_error {
+            builder = builder.on_input_error(on_input_error);
+        }
+        for rule in self.alerts {
+            builder = builder.alert(rule);
+        }
+        builder.clock(clock)
+    }
+
+    /// Check that the pipeline has exactly one source of values, either an input or a valid graph
+    /// of nodes.
+    pub fn check(&self) -> Result<(), BuildError> {
+        match (&self.input, &self.from) {
+            (Some(_), None) if self.nodes.is_empty() => Ok(()),
+            (Some(_), _) => Err(BuildError::AmbiguousInput),
+            (None, Some(from)) => graph::check(&self.nodes, from).map_err(BuildError::Graph),
+            (None, None) => Err(BuildError::MissingInput),
+        }
+    }
+
+    /// Check that the devices and commands used by the inputs and the output of this pipeline are
+    /// available; unlike [validate][Validate::validate], the result depends on the machine.
+    pub fn check_devices(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        if let Some(input) = &self.input {
+            if let Err(err) = input.check_device() {
+                errors.push(ValidationError::new("input", err));
+            }
+        }
+        for (name, node) in &self.nodes {
+            if let Node::Input(input) = node {
+                if let Err(err) = input.check_device() {
+                    errors.push(ValidationError::new(format!("nodes.{}.Input", name), err));
+                }
+            }
+        }
+        if let Err(err) = self.output.check_device() {
+            errors.push(ValidationError::new("output", err));
+        }
+        if let Some(tachometer) = &self.tachometer {
+            if let Err(err) = tachometer.check_device() {
+                errors.push(ValidationError::new("tachometer", err));
+            }
+        }
+        errors
+    }
+
+    /// Whether `stage` is the id of a stage of this pipeline (see [AlertRule::stage]).
+    fn has_stage(&self, stage: &str) -> bool {
+        let operation = |operations: &[Box<dyn OperationParameters>], index: &str| {
+            index
+                .parse::<usize>()
+                .is_ok_and(|index| index < operations.len())
+        };
+        match stage {
+            "input" | "output" => true,
+            "tachometer" => self.tachometer.is_some(),
+            _ => {
+                operation(&self.operations, stage)
+                    || self.nodes.contains_key(stage)
+                    || stage.split_once('/').is_some_and(|(node, index)| {
+                        matches!(self.nodes.get(node), Some(Node::Chain { operations, .. })
+                            if operation(operations, index))
+                    })
+            }
+        }
+    }
+
+    /// Build the (unmonitored) control loop described by this pipeline.
+    pub fn build(self) -> Result<ControlLoop, BuildError> {
+        self.into_builder().build()
+    }
+
+    /// Run the control loop described by this pipeline on the current thread (see
+    /// [ControlLoop::run_blocking]).
+    pub fn run_blocking(self) -> Result<(), BuildError> {
+        self.build().map(ControlLoop::run_blocking)
+    }
+
+    /// Run the control loop described by this pipeline on a new thread (see
+    /// [ControlLoop::spawn]).
+    pub fn spawn(self) -> Result<PipelineHandle, BuildError> {
+        self.build().map(ControlLoop::spawn)
+    }
+}
+
+/// Validate the source of values, the operations and the sample rate of a pipeline.
+impl Validate for Pipeline {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        if let Err(err) = self.check() {
+            let path = match &err {
+                BuildError::Graph(GraphError::UnknownNode { node, .. }) if node == "(pipeline)" => {
+                    "from".to_string()
+                }
+                BuildError::Graph(GraphError::UnknownNode { node, .. })
+                | BuildError::Graph(GraphError::InvalidJoin(node))
+                | BuildError::Graph(GraphError::UnevenConsumers(node)) => format!("nodes.{}", node),
+                BuildError::Graph(GraphError::Cycle(nodes)) => format!("nodes.{}", nodes[0]),
+                _ => "input".to_string(),
             };
-            // FIXME: the code below defeats the purpose of having the operation trait...
-            // need to figure out how to solve this... eventually some match like below will
-            // show up somewhere to deal with the different operations, but at this point here
-            // we shouldn't need to match I think...
-            // TODO: The above can be fixed by implementing the same approach as in
-            // (i3status-rust)[https://github.com/greshake/i3status-rust] for blocks.
-            // A match would still be required but things can be simplified by a simple macro like
-            // they do on their codebase. It would also enable to have common config or fields
-            // across operations (such as the monitor!)
-            last_iterator = match operation {
-                OperationParameters::Identity(op) => Box::new(op.apply(last_iterator, local_tx)),
-                OperationParameters::PID(op) => Box::new(op.apply(last_iterator, local_tx)),
-                OperationParameters::DampenedOscillator(op) => {
-                    Box::new(op.apply(last_iterator, local_tx))
+            errors.push(ValidationError::new(path, err.to_string()));
+        }
+        if let Some(input) = &self.input {
+            errors.extend(input.validate().into_iter().map(|err| err.within("input")));
+        }
+        for (name, node) in &self.nodes {
+            match node {
+                Node::Input(input) => errors.extend(
+                    input
+                        .validate()
+                        .into_iter()
+                        .map(|err| err.within(&format!("nodes.{}.Input", name))),
+                ),
+                Node::Chain { operations, .. } => errors.extend(validate_operations(
+                    operations,
+                    &format!("nodes.{}.Chain", name),
+                )),
+                Node::Join { .. } => {}
+            }
+        }
+        errors.extend(validate_operations(&self.operations, ""));
+        if let Some(tachometer) = &self.tachometer {
+            errors.extend(
+                tachometer
+                    .params()
+                    .validate()
+                    .into_iter()
+                    .map(|err| err.within("tachometer")),
+            );
+        }
+        if let Some(stall) = &self.stall {
+            if self.tachometer.is_none() {
+                errors.push(ValidationError::new(
+                    "stall",
+                    "detecting a stalled fan requires a tachometer",
+                ));
+            }
+            errors.extend(stall.validate().into_iter().map(|err| err.within("stall")));
+        }
+        if let Some(over_temperature) = &self.over_temperature {
+            errors.extend(
+                over_temperature
+                    .validate()
+                    .into_iter()
+                    .map(|err| err.within("over_temperature")),
+            );
+        }
+        if let Some(on_input_error) = &self.on_input_error {
+            if self.input.is_none() {
+                errors.push(ValidationError::new(
+                    "on_input_error",
+                    "only applies to an input, not to the inputs of nodes (which end the loop when \
+                     they fail)",
+                ));
+            }
+            errors.extend(
+                on_input_error
+                    .validate()
+                    .into_iter()
+                    .map(|err| err.within("on_input_error")),
+            );
+        }
+        for (index, rule) in self.alerts.iter().enumerate() {
+            let path = format!("alerts[{}]", index);
+            errors.extend(rule.validate().into_iter().map(|err| err.within(&path)));
+            if rule.when == Condition::Error && rule.stage == "input" {
+                let message = if self.input.is_none() {
+                    Some("errors of the inputs of nodes are not counted (they end the loop)")
+                } else if self.on_input_error.is_none() {
+                    Some("the loop stops at the first error of its input, unless on_input_error is given")
+                } else {
+                    None
+                };
+                if let Some(message) = message {
+                    errors.push(ValidationError::new(format!("{}.when", path), message));
                 }
-                OperationParameters::Clip(op) => Box::new(op.apply(last_iterator, local_tx)),
-                OperationParameters::AtLeast(op) => Box::new(op.apply(last_iterator, local_tx)),
-                OperationParameters::Supersample(op) => Box::new(op.apply(last_iterator, local_tx)),
-                OperationParameters::Subsample(op) => Box::new(op.apply(last_iterator, local_tx)),
-                OperationParameters::Average(op) => Box::new(op.apply(last_iterator, local_tx)),
-            }
-        }
-        // TODO: Below code should be generalized if more outputs are to be implemented; is here a
-        // good point to call the constructors? How to generalize over different types? How to deal
-        // with errors?
-        let output: Box<dyn crate::outputs::Pushable + Send> = match self.output {
-            Output::PWM => Box::new(PWM::new().unwrap()),
-            Output::External(cmd) => Box::new(External { cmd }),
+            }
+            if !self.has_stage(&rule.stage) {
+                errors.push(ValidationError::new(
+                    format!("{}.stage", path),
+                    format!("the pipeline has no stage {}", rule.stage),
+                ));
+            }
+            if self.alerts[..index]
+                .iter()
+                .any(|other| other.name == rule.name)
+            {
+                errors.push(ValidationError::new(
+                    format!("{}.name", path),
+                    format!("another alert is named {}", rule.name),
+                ));
+            }
+        }
+        if self.sample_rate == 0 {
+            errors.push(ValidationError::new(
+                "sample_rate",
+                "must be at least 1 (milliseconds)",
+            ));
+        }
+        errors
+    }
+}
+
+/// Validate a list of operations found at `path` (at the top level if empty).
+fn validate_operations(
+    operations: &[Box<dyn OperationParameters>],
+    path: &str,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    for (index, operation) in operations.iter().enumerate() {
+        let prefix = format!("operations[{}]", index);
+        for err in operation.validate() {
+            let err = err.within(&prefix);
+            errors.push(if path.is_empty() {
+                err
+            } else {
+                err.within(path)
+            });
+        }
+    }
+    errors
+}
+
+/// Errors when building a [ControlLoop] out of a [PipelineBuilder].
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    MissingInput,
+    /// Both an input and a graph of nodes were given
+    AmbiguousInput,
+    MissingOutput,
+    Graph(GraphError),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::MissingInput => {
+                write!(f, "the pipeline has no input (nor nodes and `from`)")
+            }
+            BuildError::AmbiguousInput => {
+                write!(f, "the pipeline has both an input and nodes or `from`")
+            }
+            BuildError::MissingOutput => write!(f, "the pipeline has no output"),
+            BuildError::Graph(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Smallest change of the values of a pipeline which is pushed to its output.
+const PUSH_THRESHOLD: f64 = 0.005;
+
+/// Builder of a [ControlLoop]; see [Pipeline::builder].
+pub struct PipelineBuilder {
+    name: Option<String>,
+    input: Option<Box<dyn Iterator<Item = Result<f64, String>> + Send>>,
+    nodes: BTreeMap<String, Node>,
+    from: Option<String>,
+    operations: Vec<Box<dyn OperationParameters>>,
+    output: Option<Box<dyn Pushable + Send>>,
+    sample_rate: u64,
+    monitored: bool,
+    monitor_tx: Option<mpsc::Sender<String>>,
+    store: Option<Arc<StateStore>>,
+    recorder: Option<Arc<Recorder>>,
+    clock: Option<SharedClock>,
+    tachometer: Option<Tachometer>,
+    supervisors: Vec<Box<dyn Supervisor>>,
+    alerts: Vec<AlertRule>,
+    on_input_error: Option<InputErrorParameters>,
+    /// Values of the stages, when watched by alerts
+    values: Option<StageValues>,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> PipelineBuilder {
+        PipelineBuilder {
+            name: None,
+            input: None,
+            nodes: BTreeMap::new(),
+            from: None,
+            operations: Vec::new(),
+            output: None,
+            sample_rate: default_sample_rate(),
+            monitored: false,
+            monitor_tx: None,
+            store: None,
+            recorder: None,
+            clock: None,
+            tachometer: None,
+            supervisors: Vec::new(),
+            alerts: Vec::new(),
+            on_input_error: None,
+            values: None,
+        }
+    }
+}
+
+impl PipelineBuilder {
+    /// Name of the pipeline; it is used to tell apart the monitoring messages of several
+    /// pipelines, which are then prefixed by `name/`.
+    pub fn name<S>(mut self, name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The input of the pipeline; any iterator of values (such as an [Input]) will do. Values are
+    /// stamped with the time at which they are produced.
+    pub fn input<I>(mut self, input: I) -> Self
+    where
+        I: Iterator<Item = f64> + Send + 'static,
+    {
+        self.input = Some(Box::new(input.map(Ok)));
+        self
+    }
+
+    /// The input of the pipeline, as an iterator of values which could not always be read (such
+    /// as [Input::readings]). The loop stops at the first value which cannot be read, unless told
+    /// otherwise with [on_input_error][PipelineBuilder::on_input_error].
+    pub fn fallible_input<I>(mut self, input: I) -> Self
+    where
+        I: Iterator<Item = Result<f64, String>> + Send + 'static,
+    {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Add a node to the graph of the pipeline; the graph is used instead of an
+    /// [input][PipelineBuilder::input] (see [from][PipelineBuilder::from]).
+    pub fn node<S>(mut self, name: S, node: Node) -> Self
+    where
+        S: Into<String>,
+    {
+        self.nodes.insert(name.into(), node);
+        self
+    }
+
+    /// The node of the graph whose output feeds the operations of the pipeline.
+    pub fn from<S>(mut self, from: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.from = Some(from.into());
+        self
+    }
+
+    /// Append an operation to the pipeline.
+    pub fn then<O>(self, operation: O) -> Self
+    where
+        O: OperationParameters + 'static,
+    {
+        self.then_boxed(Box::new(operation))
+    }
+
+    /// Append an already boxed operation (e.g. one read from a config file) to the pipeline.
+    pub fn then_boxed(mut self, operation: Box<dyn OperationParameters>) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// The output of the pipeline.
+    pub fn output<P>(mut self, output: P) -> Self
+    where
+        P: Pushable + Send + 'static,
+    {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    /// Period of the control loop, in milliseconds (defaults to 1000).
+    pub fn sample_rate(mut self, sample_rate: u64) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Whether the operations (and the scheduler) should report their internal state through the
+    /// channel returned by [ControlLoop::monitor] (defaults to `false`).
+    pub fn monitored(mut self, monitored: bool) -> Self {
+        self.monitored = monitored;
+        self
+    }
+
+    /// Monitor the pipeline, but report its internal state through the given channel instead of a
+    /// new one; this allows several pipelines to share a single channel (see
+    /// [name][PipelineBuilder::name]).
+    pub fn monitor_with(mut self, tx: mpsc::Sender<String>) -> Self {
+        self.monitored = true;
+        self.monitor_tx = Some(tx);
+        self
+    }
+
+    /// Keep the state of the operations in `store` (see [crate::state]); operations whose slot in
+    /// the store already holds a state start from it. Slots have the same ids as monitoring
+    /// messages, so several (named) pipelines can share a store.
+    pub fn persist_with(mut self, store: Arc<StateStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Record the samples of the input and of every operation with `recorder` (see
+    /// [crate::recording]); stages have the same ids as monitoring messages, so several (named)
+    /// pipelines can share a recorder.
+    pub fn record_with(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// The clock from which the times of samples and the deadlines of the control loop are taken
+    /// (real time by default); with a [VirtualClock][crate::clock::VirtualClock], the loop runs as
+    /// fast as possible (see [simulation::run_for][crate::simulation::run_for]). Note that
+    /// the time of a simulated [Input] must be set separately (see
+    /// [Pipeline::into_builder_with_clock]).
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Read the speed of the fan driven by the pipeline with `tachometer` after every sample (see
+    /// [LoopState::last_rpm]); it is reported as `tachometer` in monitoring messages and
+    /// recordings.
+    pub fn tachometer(mut self, tachometer: Tachometer) -> Self {
+        self.tachometer = Some(tachometer);
+        self
+    }
+
+    /// Watch the state of the loop with `supervisor` after every sample (see [crate::alarms]);
+    /// its alarms are reported with ids prefixed by the name of the pipeline.
+    pub fn supervised_by<S>(mut self, supervisor: S) -> Self
+    where
+        S: Supervisor + 'static,
+    {
+        self.supervisors.push(Box::new(supervisor));
+        self
+    }
+
+    /// Replace the values of a [fallible input][PipelineBuilder::fallible_input] which cannot be
+    /// read by the last one, and take over the output when it keeps failing (see
+    /// [InputErrorParameters]).
+    pub fn on_input_error(mut self, params: InputErrorParameters) -> Self {
+        self.on_input_error = Some(params);
+        self
+    }
+
+    /// Raise an alarm whenever `rule` holds (see [AlertRule]).
+    pub fn alert(mut self, rule: AlertRule) -> Self {
+        self.alerts.push(rule);
+        self
+    }
+
+    /// Time between two values taken by `operations` (the last ones of the pipeline), in seconds.
+    fn period_of(&self, operations: &[Box<dyn OperationParameters>]) -> f64 {
+        let (calls, ticks) = values_taken(operations);
+        self.sample_rate as f64 / 1000.0 * ticks as f64 / calls as f64
+    }
+
+    fn monitor_prefix(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}/", name),
+            None => String::new(),
+        }
+    }
+
+    fn monitor_id<T: std::fmt::Display>(&self, id: T) -> String {
+        format!("{}{}", self.monitor_prefix(), id)
+    }
+
+    /// Record the samples of `iter` as stage `id` (prefixed by the name of the pipeline), if
+    /// recording, and keep its last value if watched by alerts.
+    fn record<T: std::fmt::Display>(&self, iter: BoxedIterator, id: T) -> BoxedIterator {
+        self.tap(iter, self.monitor_id(id))
+    }
+
+    /// Same as [record][PipelineBuilder::record], with an already prefixed id.
+    fn tap(&self, iter: BoxedIterator, id: String) -> BoxedIterator {
+        let iter = match &self.values {
+            Some(values) => values.tap(iter, id.clone()),
+            None => iter,
+        };
+        match &self.recorder {
+            Some(recorder) => recorder.tap(iter, id),
+            None => iter,
+        }
+    }
+
+    pub fn build(mut self) -> Result<ControlLoop, BuildError> {
+        let output = self.output.take().ok_or(BuildError::MissingOutput)?;
+        let (tx, rx) = match (self.monitored, &self.monitor_tx) {
+            (true, Some(tx)) => (Some(tx.clone()), None),
+            (true, None) => {
+                let (tx, rx) = mpsc::channel();
+                (Some(tx), Some(rx))
+            }
+            (false, _) => (None, None),
         };
+        let clock = self.clock.take().unwrap_or_else(SystemClock::shared);
+        let state = Arc::new(Mutex::new(LoopState::default()));
+        if !self.alerts.is_empty() {
+            self.values = Some(StageValues::default());
+        }
+        let nodes = std::mem::take(&mut self.nodes);
+        let (input, from) = (self.input.take(), self.from.take());
+        let source: BoxedIterator = match (input, from) {
+            (Some(input), None) if nodes.is_empty() => {
+                // with a failsafe, the last value keeps the loop going while the failsafe is pushed
+                let max_errors = match self.on_input_error.take() {
+                    Some(params) if params.failsafe.is_some() => {
+                        self.supervisors.push(Box::new(InputFailure::new(params)));
+                        None
+                    }
+                    Some(params) => Some(params.max_errors),
+                    None => Some(0),
+                };
+                let input = HoldOnError::new(input, max_errors, Arc::clone(&state));
+                Box::new(Timestamped::with_clock(input, clock.clone()))
+            }
+            (Some(_), _) => return Err(BuildError::AmbiguousInput),
+            (None, Some(from)) => graph::build(
+                nodes,
+                &from,
+                self.period_of(&self.operations),
+                &self.monitor_prefix(),
+                tx.as_ref(),
+                self.store.as_deref(),
+                &|iter, id| self.tap(iter, id),
+                clock.clone(),
+                &state,
+            )
+            .map_err(BuildError::Graph)?,
+            (None, None) => return Err(BuildError::MissingInput),
+        };
+        let source = self.record(source, "input");
+        let mut last_iterator: BoxedIterator = Box::new(SourceTap {
+            source,
+            state: Arc::clone(&state),
+        });
+        let operations = std::mem::take(&mut self.operations);
+        let periods: Vec<f64> = (0..operations.len())
+            .map(|index| self.period_of(&operations[index + 1..]))
+            .collect();
+        for (index, mut operation) in operations.into_iter().enumerate() {
+            operation.set_period(periods[index]);
+            let local_tx = tx.as_ref().map(|tx| Monitor {
+                id: self.monitor_id(index),
+                tx: tx.clone(),
+            });
+            let slot = self
+                .store
+                .as_ref()
+                .map(|store| store.slot(&self.monitor_id(index)));
+            last_iterator = operation.build(last_iterator, local_tx, slot);
+            last_iterator = self.record(last_iterator, index);
+        }
+        let tachometer = self.tachometer.take().map(|mut tachometer| {
+            tachometer.set_clock(clock.clone());
+            TachometerTap {
+                tachometer,
+                id: self.monitor_id("tachometer"),
+                recorder: self.recorder.clone(),
+                failing: false,
+            }
+        });
+        let alerts = std::mem::take(&mut self.alerts);
+        if let Some(values) = self.values.take() {
+            let alerts = Alerts::new(alerts, values, &self.monitor_prefix());
+            self.supervisors.push(Box::new(alerts));
+        }
+        Ok(ControlLoop {
+            monitor_prefix: self.monitor_prefix(),
+            supervisors: std::mem::take(&mut self.supervisors),
+            failsafe: None,
+            source: last_iterator,
+            output,
+            sample_rate: self.sample_rate,
+            scheduler_id: self.monitor_id("scheduler"),
+            tx,
+            rx,
+            state,
+            stop: Arc::new(AtomicBool::new(false)),
+            clock,
+            tachometer,
+        })
+    }
+}
 
-        // If running in monitored mode, spawn a new thread, otherwise run pipeline in current
-        // thread.
-        // TODO: This behaviour is quite unexpected, best solution would be to have two functions,
-        // one which spawns a new thread (regardless of monitoring) and another which doesn't.
-        if monitored {
-            std::thread::spawn(move || sample_forever(last_iterator, output, sample_rate));
-            Some(rx)
-        } else {
-            sample_forever(last_iterator, output, sample_rate);
-            None
+/// Iterator adaptor which replaces the values of an input which cannot be read by the previous one,
+/// and counts the consecutive errors in the [LoopState]. It ends after more than `max_errors`
+/// consecutive errors, if given (and then tells the [LoopState] why the loop stopped).
+pub(crate) struct HoldOnError {
+    input: Box<dyn Iterator<Item = Result<f64, String>> + Send>,
+    last: Option<f64>,
+    max_errors: Option<u32>,
+    state: Arc<Mutex<LoopState>>,
+}
+
+impl HoldOnError {
+    pub(crate) fn new(
+        input: Box<dyn Iterator<Item = Result<f64, String>> + Send>,
+        max_errors: Option<u32>,
+        state: Arc<Mutex<LoopState>>,
+    ) -> HoldOnError {
+        HoldOnError {
+            input,
+            last: None,
+            max_errors,
+            state,
+        }
+    }
+}
+
+impl Iterator for HoldOnError {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        let value = self.input.next()?;
+        let mut state = self.state.lock().unwrap();
+        match value {
+            Ok(value) => {
+                if state.input_errors > 0 {
+                    info!("Input can be read again");
+                    state.input_errors = 0;
+                }
+                self.last = Some(value);
+                Some(value)
+            }
+            Err(err) => {
+                if state.input_errors == 0 {
+                    error!("Failed to read input: {}", err);
+                }
+                state.input_errors += 1;
+                if self.last.is_none() {
+                    error!("Stopping, as the input could never be read");
+                    state.stopped = Some(StopReason::InputFailed);
+                    return None;
+                }
+                if self
+                    .max_errors
+                    .is_some_and(|max_errors| state.input_errors > max_errors)
+                {
+                    error!(
+                        "Stopping, as the input failed {} times in a row",
+                        state.input_errors
+                    );
+                    state.stopped = Some(StopReason::InputFailed);
+                    return None;
+                }
+                self.last
+            }
+        }
+    }
+}
+
+/// Iterator adaptor which records the samples of the source of a pipeline (before its operations)
+/// in the [LoopState].
+struct SourceTap {
+    source: BoxedIterator,
+    state: Arc<Mutex<LoopState>>,
+}
+
+impl Iterator for SourceTap {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let sample = self.source.next()?;
+        self.state.lock().unwrap().last_input = Some(sample);
+        Some(sample)
+    }
+}
+
+/// The tachometer read next to a [ControlLoop] (see [PipelineBuilder::tachometer]).
+struct TachometerTap {
+    tachometer: Tachometer,
+    id: String,
+    recorder: Option<Arc<Recorder>>,
+    /// Whether the last reading failed, so that failures are only logged when they start
+    failing: bool,
+}
+
+impl TachometerTap {
+    /// Read the speed of the fan at `time`, and report it to the monitor and the recorder.
+    fn read(&mut self, time: f64, tx: Option<&mpsc::Sender<String>>) -> Option<f64> {
+        match self.tachometer.read() {
+            Ok(rpm) => {
+                if self.failing {
+                    info!("Tachometer {} can be read again", self.id);
+                    self.failing = false;
+                }
+                if let Some(recorder) = &self.recorder {
+                    recorder.record(&self.id, Sample::new(rpm, time));
+                }
+                if let Some(tx) = tx {
+                    tx.send(format!("{}: Tachometer: {{\"rpm\":{}}}\n", self.id, rpm))
+                        .expect("Failed to send data to monitor; main thread must have crashed.");
+                }
+                Some(rpm)
+            }
+            Err(err) => {
+                if !self.failing {
+                    error!("Failed to read tachometer {}: {}", self.id, err);
+                    self.failing = true;
+                }
+                None
+            }
         }
     }
 }
+
+/// Externally visible state of a [ControlLoop].
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct LoopState {
+    /// Number of samples that went through the pipeline so far
+    pub ticks: u64,
+    /// Last sample of the source of the pipeline (its input, or node `from`)
+    pub last_input: Option<Sample>,
+    /// Number of consecutive samples for which the input could not be read (and its previous
+    /// value was used instead, see [InputErrorParameters])
+    pub input_errors: u32,
+    /// Last sample produced by the pipeline
+    pub last_sample: Option<Sample>,
+    /// Last value pushed to the output
+    pub last_pushed: Option<f64>,
+    /// Speed of the fan read after the last sample, in RPM (with a
+    /// [tachometer][PipelineBuilder::tachometer], if it could be read)
+    pub last_rpm: Option<f64>,
+    /// Statistics of the scheduler (not updated when driven with [ControlLoop::tick])
+    pub scheduler: SchedulerStats,
+    /// Why the loop stopped, once it did
+    pub stopped: Option<StopReason>,
+}
+
+/// Why a control loop stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum StopReason {
+    /// Its input ended, e.g. at the end of a recording
+    InputEnded,
+    /// Its input (or the input of one of its nodes) could not be read, see
+    /// [InputErrorParameters]
+    InputFailed,
+    /// It was told to stop (see [PipelineHandle::stop])
+    Stopped,
+}
+
+/// A built pipeline: an iterator of samples feeding an output. It can be driven one sample at a
+/// time with [tick][ControlLoop::tick], or periodically with [run_blocking][ControlLoop::run_blocking]
+/// or [spawn][ControlLoop::spawn] (which runs it on a new thread); [spawn_paused][ControlLoop::spawn_paused]
+/// runs it on a new thread one sample at a time, on request. Whether it is monitored or not
+/// is decided when building it (see [PipelineBuilder::monitored]).
+pub struct ControlLoop {
+    source: BoxedIterator,
+    output: Box<dyn Pushable + Send>,
+    sample_rate: u64,
+    scheduler_id: String,
+    tx: Option<mpsc::Sender<String>>,
+    rx: Option<mpsc::Receiver<String>>,
+    state: Arc<Mutex<LoopState>>,
+    stop: Arc<AtomicBool>,
+    clock: SharedClock,
+    tachometer: Option<TachometerTap>,
+    monitor_prefix: String,
+    supervisors: Vec<Box<dyn Supervisor>>,
+    /// Value pushed to the output instead of the samples while an alarm lasts
+    failsafe: Option<f64>,
+}
+
+impl ControlLoop {
+    /// The channel through which the internal state of the operations is reported, if the
+    /// pipeline was built as monitored (and not with [PipelineBuilder::monitor_with]). This can
+    /// only be taken once.
+    pub fn monitor(&mut self) -> Option<mpsc::Receiver<String>> {
+        self.rx.take()
+    }
+
+    pub fn state(&self) -> LoopState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Time between two samples.
+    pub fn period(&self) -> Duration {
+        Duration::from_millis(self.sample_rate)
+    }
+
+    /// Pull a single sample through the pipeline and push it to the output; returns `None` if the
+    /// input is exhausted. NOTE: The current implementation *will not push new values unless they
+    /// differ from the last one pushed by at least 0.005*. This is, of course, very arbitrary and
+    /// has to change in future versions, possibly providing an adjustable threshold.
+    ///
+    /// After every sample, the supervisors of the loop (see [PipelineBuilder::supervised_by]) look
+    /// at its state; while one of them asks for a failsafe value, that value is pushed instead.
+    pub fn tick(&mut self) -> Option<Sample> {
+        let sample = self.source.next()?;
+        let shared = Arc::clone(&self.state);
+        let mut state = shared.lock().unwrap();
+        // the first sample is always pushed, so that the output starts from a known value; the
+        // difference is taken from the last value pushed, so that slow changes add up
+        if self.failsafe.is_none()
+            && state
+                .last_pushed
+                .is_none_or(|last| (last - sample.value).abs() >= PUSH_THRESHOLD)
+        {
+            self.output.push(sample.value);
+            state.last_pushed = Some(sample.value);
+        }
+        if let Some(tachometer) = &mut self.tachometer {
+            state.last_rpm = tachometer.read(sample.time, self.tx.as_ref());
+        }
+        state.ticks += 1;
+        state.last_sample = Some(sample);
+        if !self.supervisors.is_empty() {
+            self.supervise(&mut state, sample);
+        }
+        Some(sample)
+    }
+
+    /// Let the supervisors look at `state`, report their alarms, and push their failsafe value (or
+    /// `sample` once they no longer ask for one).
+    fn supervise(&mut self, state: &mut LoopState, sample: Sample) {
+        for supervisor in &mut self.supervisors {
+            for event in supervisor.observe(state) {
+                let id = format!("{}{}", self.monitor_prefix, event.alarm);
+                event.report(&id, self.tx.as_ref());
+            }
+        }
+        let failsafe = self
+            .supervisors
+            .iter()
+            .filter_map(|supervisor| supervisor.failsafe())
+            .reduce(f64::max);
+        let value = match (failsafe, self.failsafe) {
+            (Some(value), _) => value,
+            (None, Some(_)) => sample.value,
+            (None, None) => return,
+        };
+        if state.last_pushed != Some(value) {
+            self.output.push(value);
+            state.last_pushed = Some(value);
+        }
+        self.failsafe = failsafe;
+    }
+
+    /// Run the control loop on the current thread, taking a sample every `sample_rate`
+    /// milliseconds (see [Scheduler]), until the input is exhausted or the loop is stopped (see
+    /// [PipelineHandle::stop]).
+    pub fn run_blocking(mut self) {
+        let mut scheduler = Scheduler::with_clock(self.period(), self.clock.clone());
+        let mut reason = StopReason::Stopped;
+        while !self.stop.load(Ordering::Relaxed) {
+            if self.tick().is_none() {
+                reason = StopReason::InputEnded;
+                break;
+            }
+            scheduler.wait();
+            self.state.lock().unwrap().scheduler = scheduler.stats();
+            if let Some(tx) = &self.tx {
+                let serialized = serde_json::to_string(&scheduler.stats()).unwrap();
+                tx.send(format!(
+                    "{}: Scheduler: {}\n",
+                    self.scheduler_id, serialized
+                ))
+                .expect("Failed to send data to monitor; main thread must have crashed.");
+            }
+        }
+        self.stopped(reason);
+    }
+
+    /// Record why the loop stopped, unless it already was (e.g. by [HoldOnError]).
+    fn stopped(&self, reason: StopReason) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = state.stopped.or(Some(reason));
+    }
+
+    /// Run the control loop (see [run_blocking][ControlLoop::run_blocking]) on a new thread.
+    pub fn spawn(self) -> PipelineHandle {
+        let state = Arc::clone(&self.state);
+        let stop = Arc::clone(&self.stop);
+        let period = self.period();
+        let thread = thread::spawn(move || self.run_blocking());
+        PipelineHandle {
+            thread,
+            state,
+            stop,
+            period,
+            steps: None,
+        }
+    }
+
+    /// Run the control loop on a new thread, but only take a sample when asked to with
+    /// [PipelineHandle::step] (e.g. to drive it from the event loop of another program, or in
+    /// tests). It finishes when its input is exhausted, when it is stopped, or when its handle is
+    /// dropped.
+    pub fn spawn_paused(mut self) -> PipelineHandle {
+        let state = Arc::clone(&self.state);
+        let stop = Arc::clone(&self.stop);
+        let period = self.period();
+        let (steps, requests) = mpsc::channel::<mpsc::Sender<Option<Sample>>>();
+        let thread = thread::spawn(move || {
+            let mut reason = StopReason::Stopped;
+            for reply in requests {
+                if self.stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let sample = self.tick();
+                // the caller may have given up waiting; the loop goes on anyway
+                let _ = reply.send(sample);
+                if sample.is_none() {
+                    reason = StopReason::InputEnded;
+                    break;
+                }
+            }
+            self.stopped(reason);
+        });
+        PipelineHandle {
+            thread,
+            state,
+            stop,
+            period,
+            steps: Some(steps),
+        }
+    }
+}
+
+/// Handle to a [ControlLoop] running on its own thread.
+pub struct PipelineHandle {
+    thread: thread::JoinHandle<()>,
+    state: Arc<Mutex<LoopState>>,
+    stop: Arc<AtomicBool>,
+    period: Duration,
+    /// Requests for a sample, if the loop was spawned paused
+    steps: Option<mpsc::Sender<mpsc::Sender<Option<Sample>>>>,
+}
+
+impl PipelineHandle {
+    pub fn state(&self) -> LoopState {
+        *self.state.lock().unwrap()
+    }
+
+    /// The state of the control loop, to follow it after the handle is gone (e.g. joined).
+    pub(crate) fn shared_state(&self) -> Arc<Mutex<LoopState>> {
+        Arc::clone(&self.state)
+    }
+
+    /// Time between two samples of the control loop.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Ask the control loop to stop; it will do so before its next sample.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // a paused loop only looks at the flag when asked for a sample
+        if let Some(steps) = &self.steps {
+            let (reply, _) = mpsc::channel();
+            let _ = steps.send(reply);
+        }
+    }
+
+    /// Pull a single sample through a control loop started with [ControlLoop::spawn_paused] and
+    /// push it to the output (see [ControlLoop::tick]), waiting for it to be done. Returns `None`
+    /// once the loop finished (e.g. its input is exhausted), or if it was not started paused.
+    pub fn step(&self) -> Option<Sample> {
+        let steps = self.steps.as_ref()?;
+        let (reply, sample) = mpsc::channel();
+        steps.send(reply).ok()?;
+        sample.recv().ok().flatten()
+    }
+
+    /// Whether the control loop finished, e.g. because its input ended.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Wait for the control loop to finish (see [stop][PipelineHandle::stop]).
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
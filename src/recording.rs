@@ -0,0 +1,338 @@
+//! Recording and replay of the values flowing through pipelines. A [Recorder] writes the samples of
+//! every stage of the pipelines built with it (see [PipelineBuilder::record_with]) to a file, as
+//! CSV or JSON lines; a [Replay] input plays the values of one stage of such a recording back, e.g.
+//! to run the sensor values of a production machine through a candidate configuration on a
+//! workstation.
+//!
+//! Stages have the same ids as monitoring messages: `input` for the source of a pipeline and the
+//! index of an operation for its output (e.g. `2`), prefixed by `name/` for named pipelines; in a
+//! graph, inputs and joins are recorded under the name of their node and the operations of a chain
+//! as `node/index`.
+//!
+//! [PipelineBuilder::record_with]: crate::pipeline::PipelineBuilder::record_with
+
+use crate::clock::{SharedClock, SystemClock};
+use crate::operations::parameters::BoxedIterator;
+use crate::sample::Sample;
+use crate::validation::{Checks, Validate, ValidationError};
+use log::error;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A value of a stage of a pipeline, as recorded. In CSV files the columns are `time`, `stage` and
+/// `value`; in JSON lines files every line is an object with these fields.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Time of the sample, in seconds since the start of the pipeline
+    pub time: f64,
+    pub stage: String,
+    pub value: f64,
+}
+
+/// Format of a recording, told by the extension of its file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    /// `.csv`
+    Csv,
+    /// `.jsonl`, `.ndjson` or `.json`
+    JsonLines,
+}
+
+impl RecordFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<RecordFormat> {
+        match path.as_ref().extension()?.to_str()? {
+            "csv" => Some(RecordFormat::Csv),
+            "jsonl" | "ndjson" | "json" => Some(RecordFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Read all the records of a recording.
+pub fn read_records<P: AsRef<Path>>(path: P) -> Result<Vec<Record>, String> {
+    let path = path.as_ref();
+    match RecordFormat::from_path(path) {
+        Some(RecordFormat::Csv) => csv::Reader::from_path(path)
+            .and_then(|mut reader| reader.deserialize().collect())
+            .map_err(|err| err.to_string()),
+        Some(RecordFormat::JsonLines) => std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())?
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line).map_err(|err| format!("line {}: {}", index + 1, err))
+            })
+            .collect(),
+        None => Err(format!(
+            "unknown format of recording {} (expected .csv or .jsonl)",
+            path.display()
+        )),
+    }
+}
+
+/// Writes the samples of the stages of pipelines to a file (which is truncated first). Every
+/// record is written as soon as it is produced, so that nothing is lost if the process is killed.
+pub struct Recorder {
+    format: RecordFormat,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Create (or truncate) the recording `path`; its format is told by its extension (see
+    /// [RecordFormat]).
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Recorder> {
+        let path = path.as_ref();
+        let format = RecordFormat::from_path(path).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown format of recording {} (expected .csv or .jsonl)",
+                    path.display()
+                ),
+            )
+        })?;
+        let mut file = File::create(path)?;
+        if format == RecordFormat::Csv {
+            file.write_all(b"time,stage,value\n")?;
+        }
+        Ok(Recorder {
+            format,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Write a sample of `stage`; failures are logged, but do not stop the pipeline.
+    pub fn record(&self, stage: &str, sample: Sample) {
+        let record = Record {
+            time: sample.time,
+            stage: stage.to_string(),
+            value: sample.value,
+        };
+        let line = match self.format {
+            RecordFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                writer
+                    .serialize(&record)
+                    .map_err(|err| err.to_string())
+                    .and_then(|_| writer.into_inner().map_err(|err| err.to_string()))
+            }
+            RecordFormat::JsonLines => serde_json::to_vec(&record)
+                .map(|mut line| {
+                    line.push(b'\n');
+                    line
+                })
+                .map_err(|err| err.to_string()),
+        };
+        let result = line.and_then(|line| {
+            self.file
+                .lock()
+                .unwrap()
+                .write_all(&line)
+                .map_err(|err| err.to_string())
+        });
+        if let Err(err) = result {
+            error!("Failed to record a sample of {}: {}", stage, err);
+        }
+    }
+
+    /// Record every sample produced by `iter` as a sample of `stage`.
+    pub fn tap(self: &Arc<Self>, iter: BoxedIterator, stage: String) -> BoxedIterator {
+        Box::new(Recorded {
+            iter,
+            recorder: Arc::clone(self),
+            stage,
+        })
+    }
+}
+
+struct Recorded {
+    iter: BoxedIterator,
+    recorder: Arc<Recorder>,
+    stage: String,
+}
+
+impl Iterator for Recorded {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let sample = self.iter.next()?;
+        self.recorder.record(&self.stage, sample);
+        Some(sample)
+    }
+}
+
+/// How a [Replay] plays back the values of a recording (`"original"` by default).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Timing {
+    /// Every sample takes the value recorded at the same time since the start (on the clock of
+    /// the pipeline, so that with a virtual clock the replay still runs as fast as possible)
+    #[default]
+    Original,
+    /// Every sample takes the next recorded value, whatever the time it was recorded at
+    Fast,
+}
+
+/// Description of a replayed input.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ReplayParameters {
+    /// Recording to play back (`.csv` or `.jsonl`, as written by `fand --record`)
+    pub file: String,
+    /// Stage of the recording to play back ("input" by default, i.e. the input of an unnamed
+    /// pipeline; e.g. "cpu/input" for the input of pipeline "cpu")
+    #[serde(default = "default_stage")]
+    pub stage: String,
+    /// Start again from the beginning at the end of the recording (by default the input ends
+    /// there, and so does the pipeline)
+    #[serde(default)]
+    pub r#loop: bool,
+    #[serde(default)]
+    pub timing: Timing,
+}
+
+fn default_stage() -> String {
+    "input".to_string()
+}
+
+impl Validate for ReplayParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .require(!self.file.is_empty(), "file", "must not be empty")
+            .require(!self.stage.is_empty(), "stage", "must not be empty");
+        checks.into_errors()
+    }
+}
+
+/// An input playing back the values of one stage of a recording; the recording is read when the
+/// first value is needed. It (de-)serializes as its [parameters][ReplayParameters].
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "ReplayParameters", into = "ReplayParameters")]
+pub struct Replay {
+    params: ReplayParameters,
+    /// Times and values of the stage, once read
+    values: Option<Vec<(f64, f64)>>,
+    /// Next value to play back (with fast timing)
+    index: usize,
+    clock: SharedClock,
+    /// Time of the clock at which the first value was played back (with original timing)
+    start: Option<f64>,
+}
+
+impl From<ReplayParameters> for Replay {
+    fn from(params: ReplayParameters) -> Replay {
+        Replay {
+            params,
+            values: None,
+            index: 0,
+            clock: SystemClock::shared(),
+            start: None,
+        }
+    }
+}
+
+impl From<Replay> for ReplayParameters {
+    fn from(replay: Replay) -> ReplayParameters {
+        replay.params
+    }
+}
+
+impl JsonSchema for Replay {
+    fn schema_name() -> String {
+        ReplayParameters::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        ReplayParameters::json_schema(gen)
+    }
+}
+
+impl Replay {
+    pub fn params(&self) -> &ReplayParameters {
+        &self.params
+    }
+
+    /// Take the time from `clock` (real time by default).
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+        self.start = None;
+    }
+
+    /// Read the times and values of the stage from the recording.
+    fn read(&self) -> Result<Vec<(f64, f64)>, String> {
+        let values: Vec<(f64, f64)> = read_records(&self.params.file)?
+            .into_iter()
+            .filter(|record| record.stage == self.params.stage)
+            .map(|record| (record.time, record.value))
+            .collect();
+        if values.is_empty() {
+            Err(format!(
+                "no values of stage {} in {}",
+                self.params.stage, self.params.file
+            ))
+        } else {
+            Ok(values)
+        }
+    }
+
+    /// Check that the recording can be read and has values of the stage.
+    pub fn check(&self) -> Result<(), String> {
+        self.read().map(|_| ())
+    }
+}
+
+impl Iterator for Replay {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        if self.values.is_none() {
+            match self.read() {
+                Ok(values) => self.values = Some(values),
+                Err(err) => {
+                    error!("Failed to replay {}: {}", self.params.file, err);
+                    return None;
+                }
+            }
+        }
+        let values = self.values.as_ref()?;
+        match self.params.timing {
+            Timing::Fast => {
+                if self.index >= values.len() {
+                    if !self.params.r#loop {
+                        return None;
+                    }
+                    self.index = 0;
+                }
+                self.index += 1;
+                Some(values[self.index - 1].1)
+            }
+            Timing::Original => {
+                let now = self.clock.now();
+                let start = *self.start.get_or_insert(now);
+                let first = values[0].0;
+                let span = values[values.len() - 1].0 - first;
+                let mut elapsed = now - start;
+                if elapsed > span {
+                    if !self.params.r#loop {
+                        return None;
+                    }
+                    // a loop lasts as long as the recording, plus the average time between values
+                    if values.len() > 1 && span > 0.0 {
+                        elapsed %= span * values.len() as f64 / (values.len() - 1) as f64;
+                    }
+                }
+                let index = values.partition_point(|(time, _)| time - first <= elapsed);
+                Some(values[index.max(1) - 1].1)
+            }
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// A value flowing through the control loop, together with the time at which it was sampled from
+/// the input. Times are measured in seconds on a monotonic clock, starting when the control loop
+/// starts; operations use them to work in seconds rather than in number of samples, so that the
+/// dynamics of a pipeline do not depend on its sample rate.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub value: f64,
+    pub time: f64,
+}
+
+impl Sample {
+    pub fn new(value: f64, time: f64) -> Sample {
+        Sample { value, time }
+    }
+
+    /// A new sample with the same time as `self` but a different value; this is what most
+    /// operations produce.
+    #[inline]
+    pub fn with_value(self, value: f64) -> Sample {
+        Sample { value, ..self }
+    }
+}
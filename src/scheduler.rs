@@ -0,0 +1,90 @@
+//! Scheduling of the control loop. The [Scheduler] wakes up the loop at absolute deadlines on a
+//! monotonic [Clock], so that the time spent doing the work of a tick (including waiting for a slow
+//! input) does not add up to the period of the loop.
+
+use crate::clock::{SharedClock, SystemClock};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Statistics about how well the control loop keeps up with its deadlines. Times are in
+/// milliseconds.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct SchedulerStats {
+    /// Number of ticks so far
+    pub ticks: u64,
+    /// Number of ticks whose work took longer than the period
+    pub overruns: u64,
+    /// Number of deadlines skipped because of overruns
+    pub missed: u64,
+    /// Time spent doing the work of the last tick
+    pub last_busy_ms: f64,
+    /// Delay between the last deadline and the moment the loop actually woke up
+    pub last_jitter_ms: f64,
+    /// Largest jitter so far
+    pub max_jitter_ms: f64,
+    /// Average jitter so far
+    pub mean_jitter_ms: f64,
+}
+
+/// Drift-free periodic scheduler: the `n`-th tick is due at `start + n * period`. Times are in
+/// seconds of its clock.
+pub struct Scheduler {
+    clock: SharedClock,
+    period: f64,
+    next_deadline: f64,
+    last_wake: f64,
+    stats: SchedulerStats,
+}
+
+impl Scheduler {
+    /// A new scheduler whose first deadline is one `period` from now, in real time.
+    pub fn new(period: Duration) -> Scheduler {
+        Scheduler::with_clock(period, SystemClock::shared())
+    }
+
+    /// A new scheduler whose first deadline is one `period` from now, on the given clock.
+    pub fn with_clock(period: Duration, clock: SharedClock) -> Scheduler {
+        let now = clock.now();
+        let period = period.as_secs_f64();
+        Scheduler {
+            clock,
+            period,
+            next_deadline: now + period,
+            last_wake: now,
+            stats: SchedulerStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        self.stats
+    }
+
+    /// Block until the next deadline. If the work of the current tick overran the deadline, return
+    /// immediately; if it overran more than a whole period, the deadlines that were missed
+    /// altogether are skipped (rather than running a burst of ticks to catch up).
+    pub fn wait(&mut self) {
+        let now = self.clock.now();
+        self.stats.last_busy_ms = (now - self.last_wake) * 1000.0;
+        if now < self.next_deadline {
+            self.clock.sleep_until(self.next_deadline);
+        } else {
+            self.stats.overruns += 1;
+            let behind = now - self.next_deadline;
+            let missed = if self.period > 0.0 {
+                (behind / self.period).floor()
+            } else {
+                0.0
+            };
+            self.stats.missed += missed as u64;
+            self.next_deadline += self.period * missed;
+        }
+        let woke = self.clock.now();
+        let jitter = (woke - self.next_deadline).max(0.0) * 1000.0;
+        self.stats.ticks += 1;
+        self.stats.last_jitter_ms = jitter;
+        self.stats.max_jitter_ms = self.stats.max_jitter_ms.max(jitter);
+        self.stats.mean_jitter_ms += (jitter - self.stats.mean_jitter_ms) / self.stats.ticks as f64;
+        self.last_wake = woke;
+        self.next_deadline += self.period;
+    }
+}
@@ -0,0 +1,332 @@
+//! A simulated thermal plant, to run whole pipelines in closed loop without real hardware. The
+//! plant is a body heated by a (time dependent) load and cooled towards the ambient temperature,
+//! faster the faster its fan spins:
+//!
+//! `heat_capacity * dT/dt = load(t) - (passive_cooling + fan_cooling * duty / 100) * (T - ambient)`
+//!
+//! where `duty` is the last value pushed to the simulated output of the same name, among the
+//! [Fans] of the same simulation. If the sensor
+//! has a time constant, the measured temperature follows `T` with a first order lag (making the
+//! model of second order).
+//!
+//! Together with a [VirtualClock][crate::clock::VirtualClock], whole control loops can be simulated
+//! faster than real time (see [run_for]).
+
+use crate::clock::{Clock, SharedClock, SystemClock};
+use crate::outputs::Pushable;
+use crate::pipeline::ControlLoop;
+use crate::sample::Sample;
+use crate::validation::{Checks, Validate, ValidationError};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Heat load of a simulated plant over time, in W.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Load {
+    /// The same load at all times
+    Constant(f64),
+    /// A load which changes at given times: a list of `[time, load]` pairs, in seconds since the
+    /// start and W, sorted by time (no load before the first one)
+    Steps(Vec<(f64, f64)>),
+    /// A load oscillating around `mean`
+    Sine {
+        mean: f64,
+        amplitude: f64,
+        /// Period of the oscillation, in seconds
+        period: f64,
+    },
+}
+
+impl Load {
+    /// The load at `time` seconds since the start.
+    pub fn at(&self, time: f64) -> f64 {
+        match self {
+            Load::Constant(load) => *load,
+            Load::Steps(steps) => steps
+                .iter()
+                .take_while(|(at, _)| *at <= time)
+                .last()
+                .map_or(0.0, |(_, load)| *load),
+            Load::Sine {
+                mean,
+                amplitude,
+                period,
+            } => mean + amplitude * (2.0 * std::f64::consts::PI * time / period).sin(),
+        }
+    }
+}
+
+/// Description of a simulated thermal plant.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct PlantParameters {
+    /// Name of the plant; it is cooled by the simulated output of the same name ("plant" by
+    /// default)
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// Temperature of the surroundings, in °C (25 by default)
+    #[serde(default = "default_ambient")]
+    pub ambient: f64,
+    /// Temperature at the start, in °C (the ambient temperature by default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial: Option<f64>,
+    /// Heat needed to raise the temperature by 1 °C, in J/°C
+    pub heat_capacity: f64,
+    /// Heat dissipated with the fan stopped, in W per °C above ambient
+    pub passive_cooling: f64,
+    /// Heat dissipated in addition with the fan at 100%, in W per °C above ambient
+    pub fan_cooling: f64,
+    /// Time constant of the temperature sensor, in seconds (no lag by default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor_time_constant: Option<f64>,
+    /// Heat load, in W
+    pub load: Load,
+    /// Time elapsed between samples, in seconds; if not given, the time actually elapsed is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dt: Option<f64>,
+}
+
+fn default_name() -> String {
+    "plant".to_string()
+}
+
+fn default_ambient() -> f64 {
+    25.0
+}
+
+impl Validate for PlantParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks
+            .finite("ambient", self.ambient)
+            .positive("heat_capacity", self.heat_capacity)
+            .non_negative("passive_cooling", self.passive_cooling)
+            .non_negative("fan_cooling", self.fan_cooling);
+        if let Some(initial) = self.initial {
+            checks.finite("initial", initial);
+        }
+        if let Some(time_constant) = self.sensor_time_constant {
+            checks.positive("sensor_time_constant", time_constant);
+        }
+        if let Some(dt) = self.dt {
+            checks.positive("dt", dt);
+        }
+        match &self.load {
+            Load::Constant(load) => {
+                checks.finite("load.constant", *load);
+            }
+            Load::Steps(steps) => {
+                let sorted = steps.windows(2).all(|pair| pair[0].0 <= pair[1].0);
+                checks.require(sorted, "load.steps", "must be sorted by time");
+            }
+            Load::Sine { period, .. } => {
+                checks.positive("load.sine.period", *period);
+            }
+        }
+        checks.into_errors()
+    }
+}
+
+/// The simulated fans of a simulation: the duty cycles (in %) last pushed to its simulated
+/// outputs, by name. Plants, outputs and tachometers given the same fans (e.g. those of the
+/// pipelines of a configuration, see [Pipeline::into_builder_with]) share the fans of the same
+/// name; each simulation has its own.
+///
+/// [Pipeline::into_builder_with]: crate::pipeline::Pipeline::into_builder_with
+#[derive(Clone, Default)]
+pub struct Fans(Arc<Mutex<BTreeMap<String, Arc<Mutex<f64>>>>>);
+
+impl Fans {
+    /// The duty cycle of the simulated fan with the given name; fans are stopped until a value is
+    /// pushed to them.
+    pub fn duty(&self, name: &str) -> Arc<Mutex<f64>> {
+        Arc::clone(
+            self.0
+                .lock()
+                .unwrap()
+                .entry(name.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(0.0))),
+        )
+    }
+}
+
+/// A simulated thermal plant; as an iterator it produces the measured temperature every time it
+/// is polled, after simulating the time elapsed since the previous poll. Its fan stays stopped
+/// until it is connected to some [Fans] (see [set_fans][Plant::set_fans]). It (de-)serializes as
+/// its [parameters][PlantParameters].
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "PlantParameters", into = "PlantParameters")]
+pub struct Plant {
+    params: PlantParameters,
+    /// Time simulated so far, in seconds
+    time: f64,
+    temperature: f64,
+    measured: f64,
+    clock: SharedClock,
+    last_poll: Option<f64>,
+    duty: Arc<Mutex<f64>>,
+}
+
+impl From<PlantParameters> for Plant {
+    fn from(params: PlantParameters) -> Plant {
+        let temperature = params.initial.unwrap_or(params.ambient);
+        Plant {
+            duty: Arc::new(Mutex::new(0.0)),
+            params,
+            time: 0.0,
+            temperature,
+            measured: temperature,
+            clock: SystemClock::shared(),
+            last_poll: None,
+        }
+    }
+}
+
+impl From<Plant> for PlantParameters {
+    fn from(plant: Plant) -> PlantParameters {
+        plant.params
+    }
+}
+
+impl JsonSchema for Plant {
+    fn schema_name() -> String {
+        PlantParameters::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        PlantParameters::json_schema(gen)
+    }
+}
+
+impl Plant {
+    pub fn params(&self) -> &PlantParameters {
+        &self.params
+    }
+
+    /// Take the time elapsed between polls from `clock` (real time by default).
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+        self.last_poll = None;
+    }
+
+    /// Be cooled by the fan of the same name among `fans`.
+    pub fn set_fans(&mut self, fans: &Fans) {
+        self.duty = fans.duty(&self.params.name);
+    }
+
+    /// Actual temperature of the plant (as opposed to the measured one).
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    /// Simulate `dt` seconds, with the load and the fan speed held constant over that time (the
+    /// solution is exact in that case, so any step is stable).
+    pub fn step(&mut self, dt: f64) {
+        let params = &self.params;
+        let duty = self.duty.lock().unwrap().clamp(0.0, 100.0);
+        let load = params.load.at(self.time);
+        let conductance = params.passive_cooling + params.fan_cooling * duty / 100.0;
+        self.temperature = if conductance > 0.0 {
+            let equilibrium = params.ambient + load / conductance;
+            let decay = (-conductance * dt / params.heat_capacity).exp();
+            equilibrium + (self.temperature - equilibrium) * decay
+        } else {
+            self.temperature + load * dt / params.heat_capacity
+        };
+        self.measured = match params.sensor_time_constant {
+            Some(time_constant) => {
+                let decay = (-dt / time_constant).exp();
+                self.temperature + (self.measured - self.temperature) * decay
+            }
+            None => self.temperature,
+        };
+        self.time += dt;
+    }
+}
+
+impl Iterator for Plant {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        let now = self.clock.now();
+        let dt = match (self.params.dt, self.last_poll) {
+            (Some(dt), _) => dt,
+            (None, Some(last)) => now - last,
+            (None, None) => 0.0,
+        };
+        self.last_poll = Some(now);
+        self.step(dt);
+        Some(self.measured)
+    }
+}
+
+/// The fan of a simulated plant (see [Output::Simulated][crate::outputs::Output::Simulated]).
+pub struct SimulatedFan {
+    duty: Arc<Mutex<f64>>,
+}
+
+impl SimulatedFan {
+    /// The fan with the given name among `fans`.
+    pub fn new(name: &str, fans: &Fans) -> SimulatedFan {
+        SimulatedFan {
+            duty: fans.duty(name),
+        }
+    }
+}
+
+impl Pushable for SimulatedFan {
+    fn push(&mut self, val: f64) {
+        *self.duty.lock().unwrap() = val;
+    }
+}
+
+/// Run control loops on `clock` for `duration` seconds of its time: as fast as possible on a
+/// [VirtualClock], in real time on a [SystemClock]. The loops must have been built with the same
+/// clock (see [Pipeline::into_builder_with]). Every loop takes a sample every period
+/// (starting at the current time of the clock), in the same order as they would in real time, and
+/// `trace` is called after every sample with the index of the loop which took it. Loops whose input
+/// is exhausted stop there.
+///
+/// [VirtualClock]: crate::clock::VirtualClock
+/// [Pipeline::into_builder_with]: crate::pipeline::Pipeline::into_builder_with
+pub fn run_for<F>(loops: &mut [ControlLoop], clock: &dyn Clock, duration: f64, mut trace: F)
+where
+    F: FnMut(usize, &ControlLoop, Sample),
+{
+    let start = clock.now();
+    // deadlines are computed in whole microseconds, so that times do not accumulate rounding errors
+    let periods: Vec<u128> = loops
+        .iter()
+        .map(|control| control.period().as_micros())
+        .collect();
+    // number of samples taken by each loop so far, or None once its input is exhausted
+    let mut counts: Vec<Option<u64>> = vec![Some(0); loops.len()];
+    loop {
+        let next = counts
+            .iter()
+            .enumerate()
+            .filter_map(|(index, count)| {
+                count.map(|count| {
+                    let offset = u128::from(count) * periods[index];
+                    (index, start + offset as f64 / 1e6)
+                })
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        let (index, deadline) = match next {
+            Some((index, deadline)) if deadline <= start + duration => (index, deadline),
+            _ => break,
+        };
+        clock.sleep_until(deadline);
+        counts[index] = match loops[index].tick() {
+            Some(sample) => {
+                trace(index, &loops[index], sample);
+                counts[index].map(|count| count + 1)
+            }
+            None => None,
+        };
+    }
+}
@@ -0,0 +1,288 @@
+//! Persistence of the state of operations (e.g. the integral term of a PID control, or the window
+//! of a running average) across restarts. Every operation of a pipeline built with a [StateStore]
+//! is attached to a [StateSlot] of the store; when asked, the store takes the states of the
+//! operations into a [Snapshot], which can be written to a file and restored from it when the
+//! pipeline is built again, provided the configuration did not change in the meantime.
+
+use crate::operations::parameters::BoxedIterator;
+use crate::sample::Sample;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Runtime state of an operation which can be saved and restored. Every operation registered with
+/// [register_operation][crate::register_operation] implements it.
+pub trait Persistent: Serialize {
+    /// The state of the operation; by default everything it serializes (as for monitoring).
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+
+    /// Restore a state previously returned by [save][Persistent::save]; by default nothing is
+    /// restored. Note that times of samples start from 0 again after a restart, so state which
+    /// depends on them should not be restored.
+    fn restore(&mut self, _state: &Value) {}
+}
+
+/// Set `field` to the value of `name` in `state`, if it has one of the right type (helper for
+/// implementations of [Persistent::restore]).
+pub fn restore_field<T: DeserializeOwned>(state: &Value, name: &str, field: &mut T) {
+    if let Some(val) = state.get(name).and_then(|val| T::deserialize(val).ok()) {
+        *field = val;
+    }
+}
+
+/// Takes the current state of an operation; `None` if the operation is busy.
+type StateSource = Box<dyn Fn() -> Option<Value> + Send>;
+
+/// The state of an operation, shared between the operation and the [StateStore]: the state is only
+/// taken from the operation when asked for.
+#[derive(Clone, Default)]
+pub struct StateSlot(Arc<Mutex<SlotContents>>);
+
+#[derive(Default)]
+struct SlotContents {
+    /// The state last taken from the operation (or restored)
+    state: Option<Value>,
+    /// Type of the operation last attached, if any; the state of an operation of another type is
+    /// not restored
+    kind: Option<&'static str>,
+    /// The running operation, with the address of its shared state to tell it from another one
+    operation: Option<(usize, StateSource)>,
+}
+
+impl StateSlot {
+    /// The current state of the operation attached to the slot; the state last taken if it is busy
+    /// computing a sample, or the state it ended with (or the one restored) if none is running.
+    pub fn get(&self) -> Option<Value> {
+        let mut contents = self.0.lock().unwrap();
+        if let Some(state) = contents.operation.as_ref().and_then(|(_, source)| source()) {
+            contents.state = Some(state);
+        }
+        contents.state.clone()
+    }
+
+    pub fn set(&self, state: Value) {
+        self.0.lock().unwrap().state = Some(state);
+    }
+
+    /// The state to start an operation of type `kind` from, if any.
+    fn state_for(&self, kind: &'static str) -> Option<Value> {
+        let state = self.get();
+        let contents = self.0.lock().unwrap();
+        match contents.kind {
+            Some(other) if other != kind => None,
+            _ => state,
+        }
+    }
+
+    fn attach(&self, kind: &'static str, owner: usize, source: StateSource) {
+        let mut contents = self.0.lock().unwrap();
+        contents.kind = Some(kind);
+        contents.operation = Some((owner, source));
+    }
+
+    /// Keep `state` as the final state of the operation `owner`, unless another one replaced it.
+    fn detach(&self, owner: usize, state: Value) {
+        let mut contents = self.0.lock().unwrap();
+        if contents.operation.as_ref().map(|(attached, _)| *attached) == Some(owner) {
+            contents.operation = None;
+            contents.state = Some(state);
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.0.lock().unwrap().operation.is_some()
+    }
+}
+
+/// Iterator adaptor giving the slot of an operation access to its state; the operation is shared
+/// with the slot, which takes its state between two samples.
+struct Persisted<O: Persistent> {
+    operation: Arc<Mutex<O>>,
+    slot: StateSlot,
+}
+
+impl<O> Iterator for Persisted<O>
+where
+    O: Iterator<Item = Sample> + Persistent,
+{
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        self.operation.lock().unwrap().next()
+    }
+}
+
+/// The final state of an operation stays in its slot, e.g. to be carried over to the operation
+/// replacing it.
+impl<O: Persistent> Drop for Persisted<O> {
+    fn drop(&mut self) {
+        if let Ok(operation) = self.operation.lock() {
+            let owner = Arc::as_ptr(&self.operation) as usize;
+            self.slot.detach(owner, operation.save());
+        }
+    }
+}
+
+/// Box an operation, keeping its state in `slot` (if given); if the slot already holds a state
+/// (of an operation of the same type, if known), the operation starts from it.
+pub fn persist<O>(mut operation: O, slot: Option<StateSlot>) -> BoxedIterator
+where
+    O: Iterator<Item = Sample> + Persistent + Send + 'static,
+{
+    match slot {
+        Some(slot) => {
+            let kind = std::any::type_name::<O>();
+            if let Some(state) = slot.state_for(kind) {
+                operation.restore(&state);
+            }
+            let operation = Arc::new(Mutex::new(operation));
+            let shared: Weak<Mutex<O>> = Arc::downgrade(&operation);
+            slot.attach(
+                kind,
+                Arc::as_ptr(&operation) as usize,
+                Box::new(move || {
+                    let operation = shared.upgrade()?;
+                    let state = operation.try_lock().ok()?.save();
+                    Some(state)
+                }),
+            );
+            Box::new(Persisted { operation, slot })
+        }
+        None => Box::new(operation),
+    }
+}
+
+/// The state of all the operations of a configuration, by id (the same as for monitoring, e.g.
+/// `cpu/2`), together with the hash of the configuration.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Snapshot {
+    pub config_hash: String,
+    pub operations: BTreeMap<String, Value>,
+}
+
+/// The slots of all the operations of one or several pipelines.
+pub struct StateStore {
+    config_hash: Mutex<String>,
+    slots: Mutex<BTreeMap<String, StateSlot>>,
+}
+
+impl StateStore {
+    /// An empty store for the configuration with the given hash (see [config_hash]).
+    pub fn new(config_hash: String) -> StateStore {
+        StateStore {
+            config_hash: Mutex::new(config_hash),
+            slots: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// A store for the configuration with the given hash, restoring the snapshot saved in `path`
+    /// if it exists and was taken with the same configuration; otherwise operations start from
+    /// scratch.
+    pub fn load<P: AsRef<Path>>(path: P, config_hash: String) -> StateStore {
+        let store = StateStore::new(config_hash);
+        let path = path.as_ref();
+        let snapshot = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str::<Snapshot>(&text),
+            Err(err) => {
+                debug!("No state restored from {}: {}", path.display(), err);
+                return store;
+            }
+        };
+        match snapshot.map(|snapshot| store.restore(snapshot)) {
+            Ok(true) => debug!("Restored state from {}", path.display()),
+            Ok(false) => warn!(
+                "Configuration changed since state was saved to {}; not restoring it",
+                path.display()
+            ),
+            Err(err) => warn!("Failed to read state from {}: {}", path.display(), err),
+        }
+        store
+    }
+
+    /// Fill the slots with the states of a snapshot, unless it was taken with another
+    /// configuration; returns whether it was restored. Operations must be built after this to
+    /// start from the restored states.
+    pub fn restore(&self, snapshot: Snapshot) -> bool {
+        if snapshot.config_hash != *self.config_hash.lock().unwrap() {
+            return false;
+        }
+        let mut slots = self.slots.lock().unwrap();
+        for (id, state) in snapshot.operations {
+            slots.entry(id).or_default().set(state);
+        }
+        true
+    }
+
+    /// The slot of the operation with the given id.
+    pub fn slot(&self, id: &str) -> StateSlot {
+        self.slots
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Switch to the configuration with the given hash, e.g. when the pipelines are rebuilt from
+    /// a new configuration: the states of the operations of the previous one are carried over to
+    /// the operations of the same type with the same ids.
+    pub fn set_config_hash(&self, config_hash: String) {
+        *self.config_hash.lock().unwrap() = config_hash;
+    }
+
+    /// Forget the states of the operations which are not running (e.g. which were removed from the
+    /// configuration), once the pipelines were rebuilt.
+    pub fn prune(&self) {
+        self.slots
+            .lock()
+            .unwrap()
+            .retain(|_, slot| slot.is_running());
+    }
+
+    /// The current states of all operations (see [StateSlot::get]).
+    pub fn snapshot(&self) -> Snapshot {
+        let operations = self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(id, slot)| slot.get().map(|state| (id.clone(), state)))
+            .collect();
+        Snapshot {
+            config_hash: self.config_hash.lock().unwrap().clone(),
+            operations,
+        }
+    }
+
+    /// Write a snapshot to `path`; the file is replaced atomically, so that a crash while writing
+    /// does not lose the previous snapshot.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        let text = serde_json::to_string_pretty(&self.snapshot())?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, text)?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// Hash of a configuration, to tell whether a snapshot was taken with it. The hash is taken over
+/// its serialization, so it does not depend on formatting or comments (nor on the format of the
+/// file), and is stable across builds (FNV-1a).
+pub fn config_hash<T: Serialize>(config: &T) -> String {
+    let text = serde_json::to_string(config).unwrap_or_default();
+    let hash = text.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
@@ -0,0 +1,294 @@
+//! Integration with systemd (or any service manager implementing [sd_notify]): when `fand` runs as
+//! a `Type=notify` service, a [ServiceNotifier] tells the service manager when the control loops
+//! are ready, reports their state as the status of the service, and keeps its watchdog happy for
+//! as long as every loop makes progress. When the loops are replaced (e.g. when `fand` reloads its
+//! configuration), the notifier reports it and follows the new ones instead.
+//!
+//! Notifications are datagrams sent to the unix socket `$NOTIFY_SOCKET`, so that they can be
+//! watched without systemd, e.g. with `socat UNIX-RECV:/tmp/notify.sock -` and
+//! `NOTIFY_SOCKET=/tmp/notify.sock WATCHDOG_USEC=2000000 fand ...`.
+//!
+//! [sd_notify]: https://www.freedesktop.org/software/systemd/man/sd_notify.html
+
+use crate::pipeline::{LoopState, PipelineHandle};
+use log::{debug, error, info, warn};
+use sd_notify::NotifyState;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time between two status updates when the watchdog is disabled.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A control loop followed by a [ServiceNotifier].
+struct Watched {
+    name: Option<String>,
+    period: Duration,
+    state: Arc<Mutex<LoopState>>,
+    /// Number of samples taken when last checked
+    ticks: u64,
+    /// When the number of samples last changed
+    progress: Instant,
+}
+
+/// Notifies the service manager about the control loops of `fand`:
+/// - `READY=1` once every loop took its first sample (and pushed it to its output);
+/// - `STATUS=...` with the last input and output of every loop (and the speed of its fan, if
+///   known), every second or so;
+/// - `WATCHDOG=1` every half the watchdog timeout (`WatchdogSec=`), as long as every loop took a
+///   sample within its period plus that time. A loop blocked e.g. by a hung input is reported in
+///   the status, and the watchdog then expires.
+pub struct ServiceNotifier {
+    loops: Vec<Watched>,
+    /// Time between two watchdog notifications, if the watchdog is enabled
+    watchdog: Option<Duration>,
+    ready: bool,
+    /// Whether some loop was not making progress when last checked
+    stuck: bool,
+}
+
+impl ServiceNotifier {
+    /// A notifier for the service manager given in the environment, if any (i.e. if
+    /// `$NOTIFY_SOCKET` is set).
+    pub fn from_env() -> Option<ServiceNotifier> {
+        std::env::var_os("NOTIFY_SOCKET")?;
+        let watchdog = watchdog_timeout().map(|timeout| timeout / 2);
+        match watchdog {
+            Some(interval) => debug!("Notifying systemd, with watchdog every {:?}", interval),
+            None => debug!("Notifying systemd, without watchdog"),
+        }
+        Some(ServiceNotifier {
+            loops: Vec::new(),
+            watchdog,
+            ready: false,
+            stuck: false,
+        })
+    }
+
+    /// Follow the control loop of `handle`, named `name` in the status (if given).
+    pub fn watch(&mut self, name: Option<&str>, handle: &PipelineHandle) {
+        let period = handle.period();
+        if let Some(interval) = self.watchdog {
+            if period > interval {
+                warn!(
+                    "The period of pipeline {} ({:?}) is longer than half the watchdog timeout",
+                    name.unwrap_or("(unnamed)"),
+                    period
+                );
+            }
+        }
+        self.loops.push(Watched {
+            name: name.map(str::to_string),
+            period,
+            state: handle.shared_state(),
+            ticks: 0,
+            progress: Instant::now(),
+        });
+    }
+
+    /// Tell the service manager that the loops are being replaced (`RELOADING=1`), and stop
+    /// following them; `READY=1` is sent again once all the loops watched next pushed a value.
+    pub fn reloading(&mut self) {
+        self.loops.clear();
+        self.ready = false;
+        let result = NotifyState::monotonic_usec_now()
+            .and_then(|now| sd_notify::notify(false, &[NotifyState::Reloading, now]));
+        if let Err(err) = result {
+            error!("Failed to notify systemd: {}", err);
+        }
+    }
+
+    /// Notify the service manager periodically, on a new thread; the notifier can still be told
+    /// about new loops through the mutex.
+    pub fn spawn(notifier: Arc<Mutex<ServiceNotifier>>) -> thread::JoinHandle<()> {
+        let watchdog = notifier.lock().unwrap().watchdog;
+        let interval = watchdog.map_or(STATUS_INTERVAL, |watchdog| watchdog.min(STATUS_INTERVAL));
+        thread::spawn(move || {
+            let mut last_ping: Option<Instant> = None;
+            loop {
+                let ping = watchdog.is_some_and(|watchdog| {
+                    last_ping.is_none_or(|last_ping| last_ping.elapsed() + interval > watchdog)
+                });
+                if notifier.lock().unwrap().notify(ping) {
+                    last_ping = Some(Instant::now());
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+
+    /// Send the status of the loops, together with `READY=1` the first time they all pushed a
+    /// value to their output, and `WATCHDOG=1` if asked to and they all make progress (once started). Returns
+    /// whether the watchdog was notified.
+    fn notify(&mut self, ping: bool) -> bool {
+        let now = Instant::now();
+        let grace = self.watchdog.unwrap_or(STATUS_INTERVAL);
+        let mut status = Vec::new();
+        let mut stuck = Vec::new();
+        let mut started = true;
+        for watched in &mut self.loops {
+            let state = *watched.state.lock().unwrap();
+            if state.ticks != watched.ticks {
+                watched.ticks = state.ticks;
+                watched.progress = now;
+            }
+            let prefix = watched
+                .name
+                .as_ref()
+                .map_or(String::new(), |name| format!("{}: ", name));
+            started &= state.last_pushed.is_some();
+            let stalled_for = now - watched.progress;
+            if stalled_for > watched.period + grace {
+                stuck.push(format!("{}stuck for {}s", prefix, stalled_for.as_secs()));
+            } else {
+                status.push(format!("{}{}", prefix, describe(&state)));
+            }
+        }
+        let progressing = stuck.is_empty();
+        stuck.extend(status);
+        let status = stuck.join("; ");
+        let mut states = vec![NotifyState::Status(&status)];
+        if started && !self.ready {
+            info!("All control loops started; notifying readiness");
+            states.push(NotifyState::Ready);
+        }
+        // before the first samples, the start timeout of the service applies instead
+        let ping = ping && progressing && started;
+        if ping {
+            states.push(NotifyState::Watchdog);
+        }
+        if !progressing && !self.stuck {
+            error!("Control loops not making progress: {}", status);
+        } else if progressing && self.stuck {
+            info!("Control loops making progress again");
+        }
+        self.stuck = !progressing;
+        match sd_notify::notify(false, &states) {
+            Ok(()) => {
+                self.ready |= started;
+                ping
+            }
+            Err(err) => {
+                error!("Failed to notify systemd: {}", err);
+                false
+            }
+        }
+    }
+}
+
+/// The input and output of a control loop (and the speed of its fan), e.g.
+/// `52.3 -> 40.0 (1830 RPM)`.
+fn describe(state: &LoopState) -> String {
+    let value = |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{:.1}", value));
+    let mut description = format!(
+        "{} -> {}",
+        value(state.last_input.map(|sample| sample.value)),
+        value(state.last_pushed)
+    );
+    if let Some(rpm) = state.last_rpm {
+        description.push_str(&format!(" ({:.0} RPM)", rpm));
+    }
+    description
+}
+
+/// The watchdog timeout set by the service manager, if any: `$WATCHDOG_USEC`, unless
+/// `$WATCHDOG_PID` is set to another process (as in `sd_watchdog_enabled`).
+fn watchdog_timeout() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec)).filter(|timeout| !timeout.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Pipeline;
+    use crate::simulation::{Fans, SimulatedFan};
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::mpsc;
+
+    /// Notify once and return the notification received by `socket`.
+    fn notification(notifier: &mut ServiceNotifier, socket: &UnixDatagram) -> (bool, String) {
+        let pinged = notifier.notify(true);
+        let mut buffer = [0; 4096];
+        let length = socket.recv(&mut buffer).expect("No notification");
+        (
+            pinged,
+            String::from_utf8_lossy(&buffer[..length]).into_owned(),
+        )
+    }
+
+    fn wait_for(handle: &PipelineHandle, ticks: u64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.state().ticks < ticks {
+            assert!(Instant::now() < deadline, "The loop did not take a sample");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn notifies_readiness_status_and_watchdog() {
+        let path = std::env::temp_dir().join(format!("pifan-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        let mut notifier = ServiceNotifier {
+            loops: Vec::new(),
+            watchdog: Some(Duration::from_millis(100)),
+            ready: false,
+            stuck: false,
+        };
+
+        // a loop which takes a sample whenever it is sent a value
+        let (tx, rx) = mpsc::channel::<f64>();
+        let handle = Pipeline::builder()
+            .input(std::iter::from_fn(move || rx.recv().ok()))
+            .output(SimulatedFan::new("fan", &Fans::default()))
+            .sample_rate(10)
+            .build()
+            .unwrap()
+            .spawn();
+        notifier.watch(Some("cpu"), &handle);
+
+        let (pinged, message) = notification(&mut notifier, &socket);
+        assert!(!pinged);
+        assert!(message.contains("STATUS=cpu: - -> -"), "{}", message);
+        assert!(!message.contains("READY=1"), "{}", message);
+        assert!(!message.contains("WATCHDOG=1"), "{}", message);
+
+        // a fan stopped at first is ready all the same
+        tx.send(0.0).unwrap();
+        wait_for(&handle, 1);
+        let (pinged, message) = notification(&mut notifier, &socket);
+        assert!(pinged);
+        assert!(message.contains("STATUS=cpu: 0.0 -> 0.0"), "{}", message);
+        assert!(message.contains("READY=1"), "{}", message);
+        assert!(message.contains("WATCHDOG=1"), "{}", message);
+
+        tx.send(40.0).unwrap();
+        wait_for(&handle, 2);
+        let (pinged, message) = notification(&mut notifier, &socket);
+        assert!(pinged);
+        assert!(message.contains("STATUS=cpu: 40.0 -> 40.0"), "{}", message);
+        assert!(!message.contains("READY=1"), "{}", message);
+        assert!(message.contains("WATCHDOG=1"), "{}", message);
+
+        // the loop hangs waiting for its input, for longer than its period and the grace time
+        thread::sleep(Duration::from_millis(200));
+        let (pinged, message) = notification(&mut notifier, &socket);
+        assert!(!pinged);
+        assert!(message.contains("STATUS=cpu: stuck for"), "{}", message);
+        assert!(!message.contains("WATCHDOG=1"), "{}", message);
+
+        drop(tx);
+        handle.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,257 @@
+//! Measurement of the speed of fans. A [Tachometer] reads the speed of a fan in RPM, either by
+//! counting the pulses of its tachometer wire on a GPIO pin, or from the `fanN_input` attribute of
+//! a hwmon driver. It can be the [input][crate::inputs::Input::Tachometer] of a pipeline, or be
+//! read next to a pipeline to report the speed of the fan it drives (see
+//! [Pipeline::tachometer][crate::pipeline::Pipeline::tachometer]).
+
+use crate::clock::{SharedClock, SystemClock};
+use crate::simulation::Fans;
+use crate::validation::{Checks, Validate, ValidationError};
+use rppal::gpio::{Gpio, InputPin, Trigger};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Device through which the GPIO pins of a Raspberry Pi are accessed.
+const GPIO_DEVICE: &str = "/dev/gpiochip0";
+
+/// Where the speed of a fan is read from.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TachometerSource {
+    /// Pulses of the tachometer wire on the given GPIO pin (BCM numbering), with the internal
+    /// pull-up enabled; the pin is pulled low once or more per revolution
+    Gpio(u8),
+    /// A hwmon attribute such as `/sys/class/hwmon/hwmon2/fan1_input`, which already holds the
+    /// speed in RPM (its driver has its own `fanN_pulses` setting)
+    Hwmon(String),
+    /// The fan of the simulated plant with the given name (see [simulation][crate::simulation]),
+    /// spinning in proportion to its duty cycle
+    Simulated {
+        fan: String,
+        /// Speed at 100%, in RPM (3000 by default)
+        #[serde(default = "default_max_rpm")]
+        max_rpm: f64,
+        /// Time (in seconds since the start) from which the fan reads 0 RPM, whatever its duty
+        /// cycle, e.g. to try out stall detection; only the reading is affected, not the cooling
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stalled_after: Option<f64>,
+    },
+}
+
+fn default_max_rpm() -> f64 {
+    3000.0
+}
+
+/// Description of a tachometer.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct TachometerParameters {
+    #[serde(flatten)]
+    pub source: TachometerSource,
+    /// Pulses per revolution of the fan (2 by default, as for most PC fans); only used with a GPIO
+    /// pin
+    #[serde(default = "default_pulses_per_revolution")]
+    pub pulses_per_revolution: u32,
+}
+
+fn default_pulses_per_revolution() -> u32 {
+    2
+}
+
+impl Validate for TachometerParameters {
+    fn validate(&self) -> Vec<ValidationError> {
+        let mut checks = Checks::new();
+        checks.require(
+            self.pulses_per_revolution > 0,
+            "pulses_per_revolution",
+            "must be at least 1",
+        );
+        match &self.source {
+            TachometerSource::Gpio(_) => {}
+            TachometerSource::Hwmon(path) => {
+                checks.require(!path.is_empty(), "hwmon", "must not be empty");
+            }
+            TachometerSource::Simulated {
+                max_rpm,
+                stalled_after,
+                ..
+            } => {
+                checks.positive("simulated.max_rpm", *max_rpm);
+                if let Some(stalled_after) = stalled_after {
+                    checks.non_negative("simulated.stalled_after", *stalled_after);
+                }
+            }
+        }
+        checks.into_errors()
+    }
+}
+
+/// Pulses counted on a GPIO pin by an interrupt handler (running on a thread of its own).
+struct PulseCounter {
+    /// Kept for the interrupt to stay configured
+    _pin: InputPin,
+    pulses: Arc<AtomicU64>,
+}
+
+impl PulseCounter {
+    fn open(number: u8) -> Result<PulseCounter, String> {
+        let mut pin = Gpio::new()
+            .and_then(|gpio| gpio.get(number))
+            .map_err(|err| format!("cannot open GPIO pin {}: {}", number, err))?
+            .into_input_pullup();
+        let pulses = Arc::new(AtomicU64::new(0));
+        let counted = Arc::clone(&pulses);
+        pin.set_async_interrupt(Trigger::FallingEdge, move |_| {
+            counted.fetch_add(1, Ordering::Relaxed);
+        })
+        .map_err(|err| format!("cannot count pulses on GPIO pin {}: {}", number, err))?;
+        Ok(PulseCounter { _pin: pin, pulses })
+    }
+}
+
+/// The tachometer of a fan; as an iterator it produces the speed of the fan in RPM every time it
+/// is polled. With a GPIO pin, the speed is the average since the previous poll (the first poll
+/// only starts counting, and reads 0). It (de-)serializes as its [parameters][TachometerParameters].
+#[derive(Serialize, Deserialize)]
+#[serde(from = "TachometerParameters", into = "TachometerParameters")]
+pub struct Tachometer {
+    params: TachometerParameters,
+    /// Open once the first value is read (with a GPIO pin)
+    counter: Option<PulseCounter>,
+    clock: SharedClock,
+    /// Time of the previous poll and pulses counted until then (with a GPIO pin)
+    last_poll: Option<(f64, u64)>,
+    /// Time of the first poll (of a simulated fan)
+    start: Option<f64>,
+    /// Where a simulated fan is found
+    fans: Fans,
+}
+
+impl From<TachometerParameters> for Tachometer {
+    fn from(params: TachometerParameters) -> Tachometer {
+        Tachometer {
+            params,
+            counter: None,
+            clock: SystemClock::shared(),
+            last_poll: None,
+            start: None,
+            fans: Fans::default(),
+        }
+    }
+}
+
+impl From<Tachometer> for TachometerParameters {
+    fn from(tachometer: Tachometer) -> TachometerParameters {
+        tachometer.params
+    }
+}
+
+/// Cloning a tachometer gives a new, unopened one with the same parameters.
+impl Clone for Tachometer {
+    fn clone(&self) -> Tachometer {
+        Tachometer::from(self.params.clone())
+    }
+}
+
+impl JsonSchema for Tachometer {
+    fn schema_name() -> String {
+        TachometerParameters::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        TachometerParameters::json_schema(gen)
+    }
+}
+
+impl Tachometer {
+    pub fn params(&self) -> &TachometerParameters {
+        &self.params
+    }
+
+    /// Take the time from `clock` (real time by default).
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+        self.last_poll = None;
+        self.start = None;
+    }
+
+    /// Find a simulated fan among `fans` (it is stopped otherwise).
+    pub fn set_fans(&mut self, fans: &Fans) {
+        self.fans = fans.clone();
+    }
+
+    /// Check that the device this tachometer reads from is available.
+    pub fn check_device(&self) -> Result<(), String> {
+        match &self.params.source {
+            TachometerSource::Gpio(_) => fs::metadata(GPIO_DEVICE)
+                .map(|_| ())
+                .map_err(|err| format!("cannot find GPIO device {}: {}", GPIO_DEVICE, err)),
+            TachometerSource::Hwmon(path) => read_hwmon(path).map(|_| ()),
+            TachometerSource::Simulated { .. } => Ok(()),
+        }
+    }
+
+    /// Read the speed of the fan, in RPM.
+    pub fn read(&mut self) -> Result<f64, String> {
+        match &self.params.source {
+            TachometerSource::Gpio(pin) => {
+                if self.counter.is_none() {
+                    self.counter = Some(PulseCounter::open(*pin)?);
+                }
+                let pulses = self
+                    .counter
+                    .as_ref()
+                    .map_or(0, |counter| counter.pulses.load(Ordering::Relaxed));
+                let now = self.clock.now();
+                let rpm = match self.last_poll {
+                    Some((last, counted)) if now > last => {
+                        let revolutions = (pulses - counted) as f64
+                            / f64::from(self.params.pulses_per_revolution);
+                        revolutions / (now - last) * 60.0
+                    }
+                    _ => 0.0,
+                };
+                self.last_poll = Some((now, pulses));
+                Ok(rpm)
+            }
+            TachometerSource::Hwmon(path) => read_hwmon(path),
+            TachometerSource::Simulated {
+                fan,
+                max_rpm,
+                stalled_after,
+            } => {
+                let now = self.clock.now();
+                let elapsed = now - *self.start.get_or_insert(now);
+                if stalled_after.is_some_and(|stalled_after| elapsed >= stalled_after) {
+                    return Ok(0.0);
+                }
+                let duty = *self.fans.duty(fan).lock().unwrap();
+                Ok(max_rpm * duty.clamp(0.0, 100.0) / 100.0)
+            }
+        }
+    }
+}
+
+/// Read the speed (in RPM) held by a hwmon attribute.
+fn read_hwmon(path: &str) -> Result<f64, String> {
+    fs::read_to_string(path)
+        .map_err(|err| format!("cannot read {}: {}", path, err))?
+        .trim()
+        .parse()
+        .map_err(|err| format!("cannot parse the content of {}: {}", path, err))
+}
+
+impl Iterator for Tachometer {
+    type Item = f64;
+
+    /// The speed of the fan; the input ends if it cannot be read.
+    fn next(&mut self) -> Option<f64> {
+        self.read()
+            .map_err(|err| log::error!("Failed to read tachometer: {}", err))
+            .ok()
+    }
+}
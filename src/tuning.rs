@@ -0,0 +1,601 @@
+//! Tools to help tuning pipelines: synthetic [stimuli][Stimulus] to apply to a pipeline, the
+//! [metrics][ResponseMetrics] of its response (rise time, overshoot, settling time, ...) and plots
+//! of it. See the `fand-tune` binary.
+//!
+//! PID gains can also be suggested from a relay experiment (Åström–Hägglund): the PID control is
+//! replaced by a [relay][crate::operations::parameters::RelayParameters], which makes the process
+//! oscillate around the setpoint. The amplitude and period of the oscillation give the ultimate
+//! gain and period of the process (see [relay_ultimate_point]), from which a [TuningRule] computes
+//! the gains.
+
+use crate::clock::SharedClock;
+use crate::sample::Sample;
+use serde::Serialize;
+use std::fmt::Write;
+
+/// A synthetic input, as a function of time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stimulus {
+    /// `from` until time `at`, then `to`
+    Step { from: f64, to: f64, at: f64 },
+    /// `from` until time `start`, then changing linearly to reach `to` at time `end`
+    Ramp {
+        from: f64,
+        to: f64,
+        start: f64,
+        end: f64,
+    },
+}
+
+impl Stimulus {
+    /// The value of the stimulus at `time`.
+    pub fn at(&self, time: f64) -> f64 {
+        match *self {
+            Stimulus::Step { from, to, at } => {
+                if time < at {
+                    from
+                } else {
+                    to
+                }
+            }
+            Stimulus::Ramp {
+                from,
+                to,
+                start,
+                end,
+            } => {
+                if time <= start {
+                    from
+                } else if time >= end {
+                    to
+                } else {
+                    from + (to - from) * (time - start) / (end - start)
+                }
+            }
+        }
+    }
+
+    /// The time at which the stimulus starts changing.
+    pub fn start(&self) -> f64 {
+        match *self {
+            Stimulus::Step { at, .. } => at,
+            Stimulus::Ramp { start, .. } => start,
+        }
+    }
+
+    /// An input producing the value of the stimulus at the time of `clock`.
+    pub fn input(self, clock: SharedClock) -> StimulusInput {
+        StimulusInput {
+            stimulus: self,
+            clock,
+        }
+    }
+}
+
+/// Input producing the values of a [Stimulus].
+pub struct StimulusInput {
+    stimulus: Stimulus,
+    clock: SharedClock,
+}
+
+impl Iterator for StimulusInput {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        Some(self.stimulus.at(self.clock.now()))
+    }
+}
+
+/// Metrics of the response of a pipeline (or of a closed loop) to a change, towards a target
+/// value. Times are in seconds since the change.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ResponseMetrics {
+    /// Value of the response just before the change
+    pub initial: f64,
+    pub target: f64,
+    /// Value of the response at the end
+    pub last: f64,
+    /// Time taken to go from 10% to 90% of the way to the target (if it gets there)
+    pub rise_time: Option<f64>,
+    /// Largest excursion beyond the target, in % of the change (`initial` to `target`)
+    pub overshoot: f64,
+    /// Time after which the response stays within the band around the target (if it does)
+    pub settling_time: Option<f64>,
+    /// `target - last`
+    pub steady_state_error: f64,
+    /// Number of times the value pushed to the output changed
+    pub output_changes: u64,
+}
+
+/// Compute the metrics of `response` to a change at time `start`, towards `target` (the last value
+/// of the response if not given); the response is settled within `band` % of the change around
+/// the target. `pushed` are the values pushed to the output after every sample (if any), to count
+/// its changes. Returns `None` if there is no sample after `start`.
+pub fn analyze(
+    response: &[Sample],
+    pushed: &[Option<f64>],
+    start: f64,
+    target: Option<f64>,
+    band: f64,
+) -> Option<ResponseMetrics> {
+    let after = response.iter().position(|sample| sample.time >= start)?;
+    let initial = response[after.max(1) - 1].value;
+    let last = response.last()?.value;
+    let target = target.unwrap_or(last);
+    let change = target - initial;
+    let after = &response[after..];
+    // progress towards the target, as a fraction of the change
+    let progress = |sample: &Sample| {
+        if change == 0.0 {
+            1.0
+        } else {
+            (sample.value - initial) / change
+        }
+    };
+    let crossing = |fraction: f64| {
+        after
+            .iter()
+            .find(|sample| progress(sample) >= fraction)
+            .map(|sample| sample.time)
+    };
+    let rise_time = match (crossing(0.1), crossing(0.9)) {
+        (Some(low), Some(high)) if change != 0.0 => Some(high - low),
+        _ => None,
+    };
+    let overshoot = after
+        .iter()
+        .map(|sample| (progress(sample) - 1.0) * 100.0)
+        .fold(0.0, f64::max);
+    let tolerance = band / 100.0 * change.abs();
+    let settling_time = match after
+        .iter()
+        .rposition(|sample| (sample.value - target).abs() > tolerance)
+    {
+        None => Some(0.0),
+        Some(index) if index + 1 < after.len() => Some(after[index + 1].time - start),
+        Some(_) => None,
+    };
+    let output_changes = pushed
+        .windows(2)
+        .filter(|pair| pair[1].is_some() && pair[0] != pair[1])
+        .count() as u64;
+    Some(ResponseMetrics {
+        initial,
+        target,
+        last,
+        rise_time,
+        overshoot,
+        settling_time,
+        steady_state_error: target - last,
+        output_changes,
+    })
+}
+
+/// Ultimate gain and period of a process (at which it would oscillate under proportional control),
+/// as measured by a relay experiment.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UltimatePoint {
+    pub gain: f64,
+    /// In seconds
+    pub period: f64,
+    /// Amplitude (half the peak to peak value) of the oscillation of the input
+    pub amplitude: f64,
+    /// Number of whole oscillations measured
+    pub cycles: usize,
+}
+
+/// Measure the ultimate point from the `input` of a relay experiment around `setpoint`, whose
+/// output switched between two values `2 * relay_amplitude` apart with the given hysteresis. The
+/// first `skip` oscillations are left out (the process has not settled into its limit cycle yet).
+/// Returns `None` unless at least one whole oscillation is left.
+pub fn relay_ultimate_point(
+    input: &[Sample],
+    setpoint: f64,
+    relay_amplitude: f64,
+    hysteresis: f64,
+    skip: usize,
+) -> Option<UltimatePoint> {
+    // times at which the input crosses the setpoint upwards, interpolated between samples
+    let crossings: Vec<f64> = input
+        .windows(2)
+        .filter(|pair| pair[0].value < setpoint && pair[1].value >= setpoint)
+        .map(|pair| {
+            let fraction = (setpoint - pair[0].value) / (pair[1].value - pair[0].value);
+            pair[0].time + fraction * (pair[1].time - pair[0].time)
+        })
+        .skip(skip)
+        .collect();
+    if crossings.len() < 2 {
+        return None;
+    }
+    let (first, last) = (crossings[0], crossings[crossings.len() - 1]);
+    let cycles = crossings.len() - 1;
+    let window = input
+        .iter()
+        .filter(|sample| sample.time >= first && sample.time <= last)
+        .map(|sample| sample.value);
+    let max = window.clone().fold(f64::NEG_INFINITY, f64::max);
+    let min = window.fold(f64::INFINITY, f64::min);
+    let amplitude = (max - min) / 2.0;
+    if amplitude <= hysteresis {
+        return None;
+    }
+    let gain = 4.0 * relay_amplitude
+        / (std::f64::consts::PI * (amplitude * amplitude - hysteresis * hysteresis).sqrt());
+    Some(UltimatePoint {
+        gain,
+        period: (last - first) / cycles as f64,
+        amplitude,
+        cycles,
+    })
+}
+
+/// Gains of a PID control, with time in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Gains {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+}
+
+impl Gains {
+    /// The same gains for a control whose unit of time is `unit` seconds (e.g. the period of the
+    /// loop, for a control which integrates once per sample).
+    pub fn in_time_unit(self, unit: f64) -> Gains {
+        Gains {
+            kp: self.kp,
+            ki: self.ki * unit,
+            kd: self.kd / unit,
+        }
+    }
+}
+
+/// Rules giving the gains of a PID control from the ultimate point of the process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TuningRule {
+    /// `kp = 0.6 Ku`, `Ti = Tu / 2`, `Td = Tu / 8`: fast, but with large overshoot
+    ZieglerNichols,
+    /// `kp = Ku / 2.2`, `Ti = 2.2 Tu`, `Td = Tu / 6.3`: slower, with less overshoot and more
+    /// robust
+    TyreusLuyben,
+}
+
+impl TuningRule {
+    pub const ALL: [TuningRule; 2] = [TuningRule::ZieglerNichols, TuningRule::TyreusLuyben];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TuningRule::ZieglerNichols => "Ziegler-Nichols",
+            TuningRule::TyreusLuyben => "Tyreus-Luyben",
+        }
+    }
+
+    pub fn gains(self, point: &UltimatePoint) -> Gains {
+        let (kp, ti, td) = match self {
+            TuningRule::ZieglerNichols => {
+                (0.6 * point.gain, point.period / 2.0, point.period / 8.0)
+            }
+            TuningRule::TyreusLuyben => (point.gain / 2.2, 2.2 * point.period, point.period / 6.3),
+        };
+        Gains {
+            kp,
+            ki: kp / ti,
+            kd: kp * td,
+        }
+    }
+}
+
+/// Bounds of the values of a plot (including the target line, if any), never empty.
+fn bounds(series: &[Sample], target: Option<f64>) -> ((f64, f64), (f64, f64)) {
+    let times = series.iter().map(|sample| sample.time);
+    let values = series.iter().map(|sample| sample.value).chain(target);
+    let t_min = times.clone().fold(f64::INFINITY, f64::min);
+    let t_max = times.fold(f64::NEG_INFINITY, f64::max);
+    let v_min = values.clone().fold(f64::INFINITY, f64::min);
+    let v_max = values.fold(f64::NEG_INFINITY, f64::max);
+    let widen = |min: f64, max: f64| {
+        if !min.is_finite() || !max.is_finite() {
+            (0.0, 1.0)
+        } else if max - min < 1e-9 {
+            (min - 0.5, max + 0.5)
+        } else {
+            (min, max)
+        }
+    };
+    (widen(t_min, t_max), widen(v_min, v_max))
+}
+
+/// Plot of `series` (and of a horizontal line at `target`, if given) as text, `width` by `height`
+/// characters plus the labels of the axes.
+pub fn ascii_plot(series: &[Sample], target: Option<f64>, width: usize, height: usize) -> String {
+    let ((t_min, t_max), (v_min, v_max)) = bounds(series, target);
+    let (width, height) = (width.max(2), height.max(2));
+    let row = |value: f64| {
+        let row = ((v_max - value) / (v_max - v_min) * (height - 1) as f64).round();
+        (row.max(0.0) as usize).min(height - 1)
+    };
+    let mut grid = vec![vec![' '; width]; height];
+    if let Some(target) = target {
+        grid[row(target)] = vec!['-'; width];
+    }
+    for sample in series {
+        let column = ((sample.time - t_min) / (t_max - t_min) * (width - 1) as f64).round();
+        grid[row(sample.value)][(column.max(0.0) as usize).min(width - 1)] = '*';
+    }
+    let label = |value: f64| format!("{:>10.3}", value);
+    let mut plot = String::new();
+    for (index, line) in grid.iter().enumerate() {
+        let axis = match index {
+            0 => label(v_max),
+            i if i == height - 1 => label(v_min),
+            _ => " ".repeat(10),
+        };
+        let _ = writeln!(plot, "{} |{}", axis, line.iter().collect::<String>());
+    }
+    let _ = writeln!(plot, "{} +{}", " ".repeat(10), "-".repeat(width));
+    let _ = writeln!(
+        plot,
+        "{} {:<w$}{:>10.3}",
+        label(t_min),
+        "",
+        t_max,
+        w = width.saturating_sub(9)
+    );
+    plot
+}
+
+/// Plot of `series` (and of a horizontal line at `target`, if given) as an SVG image.
+pub fn svg_plot(series: &[Sample], target: Option<f64>, title: &str) -> String {
+    const WIDTH: f64 = 800.0;
+    const HEIGHT: f64 = 400.0;
+    const MARGIN: f64 = 60.0;
+    let ((t_min, t_max), (v_min, v_max)) = bounds(series, target);
+    let x = |time: f64| MARGIN + (time - t_min) / (t_max - t_min) * (WIDTH - 2.0 * MARGIN);
+    let y =
+        |value: f64| HEIGHT - MARGIN - (value - v_min) / (v_max - v_min) * (HEIGHT - 2.0 * MARGIN);
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="sans-serif" font-size="12">"#,
+        WIDTH, HEIGHT
+    );
+    let _ = writeln!(
+        svg,
+        r#"<rect width="100%" height="100%" fill="white"/><text x="{}" y="20" text-anchor="middle">{}</text>"#,
+        WIDTH / 2.0,
+        escape(title)
+    );
+    let _ = writeln!(
+        svg,
+        r#"<path d="M{m},{t} V{b} H{r}" fill="none" stroke="black"/>"#,
+        m = MARGIN,
+        t = MARGIN,
+        b = HEIGHT - MARGIN,
+        r = WIDTH - MARGIN
+    );
+    for (value, anchor_y) in [(v_min, y(v_min)), (v_max, y(v_max))] {
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="end">{:.3}</text>"#,
+            MARGIN - 5.0,
+            anchor_y + 4.0,
+            value
+        );
+    }
+    for (time, anchor) in [(t_min, "start"), (t_max, "end")] {
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="{}">{:.1} s</text>"#,
+            x(time),
+            HEIGHT - MARGIN + 18.0,
+            anchor,
+            time
+        );
+    }
+    if let Some(target) = target {
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{}" y1="{y}" x2="{}" y2="{y}" stroke="gray" stroke-dasharray="4"/>"#,
+            x(t_min),
+            x(t_max),
+            y = y(target)
+        );
+    }
+    let points: Vec<String> = series
+        .iter()
+        .map(|sample| format!("{:.2},{:.2}", x(sample.time), y(sample.value)))
+        .collect();
+    let _ = writeln!(
+        svg,
+        r#"<polyline points="{}" fill="none" stroke="steelblue" stroke-width="1.5"/>"#,
+        points.join(" ")
+    );
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::pipeline::Pipeline;
+    use crate::simulation;
+    use std::sync::Arc;
+
+    fn samples(values: &[(f64, f64)]) -> Vec<Sample> {
+        values
+            .iter()
+            .map(|&(time, value)| Sample::new(value, time))
+            .collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn ultimate_point_of_a_sine() {
+        let (amplitude, period) = (2.0, 8.0);
+        let input: Vec<Sample> = (0..=400)
+            .map(|step| {
+                let time = 0.05 + step as f64 * 0.1;
+                let phase = 2.0 * std::f64::consts::PI * time / period;
+                Sample::new(30.0 + amplitude * phase.sin(), time)
+            })
+            .collect();
+        // upward crossings at 8, 16, 24, 32 and 40 s, between samples
+        let point = relay_ultimate_point(&input, 30.0, 50.0, 0.5, 1).unwrap();
+        assert_eq!(point.cycles, 3);
+        assert_close(point.period, period, 1e-6);
+        // the peaks fall between samples
+        assert_close(point.amplitude, amplitude, 0.01);
+        let expected = 4.0 * 50.0 / (std::f64::consts::PI * (4.0f64 - 0.25).sqrt());
+        assert_close(point.gain, expected, 0.01 * expected);
+    }
+
+    #[test]
+    fn no_ultimate_point_without_oscillation() {
+        let rising = samples(&[(0.0, 20.0), (1.0, 25.0), (2.0, 35.0), (3.0, 40.0)]);
+        assert!(relay_ultimate_point(&rising, 30.0, 50.0, 0.0, 0).is_none());
+        // oscillations within the hysteresis are noise rather than a limit cycle
+        let small: Vec<Sample> = (0..40)
+            .map(|step| Sample::new(30.0 + if step % 2 == 0 { -0.1 } else { 0.1 }, step as f64))
+            .collect();
+        assert!(relay_ultimate_point(&small, 30.0, 50.0, 0.0, 0).is_some());
+        assert!(relay_ultimate_point(&small, 30.0, 50.0, 0.5, 0).is_none());
+        // all the oscillations are skipped
+        assert!(relay_ultimate_point(&small, 30.0, 50.0, 0.0, 20).is_none());
+    }
+
+    #[test]
+    fn tuning_rules() {
+        let point = UltimatePoint {
+            gain: 10.0,
+            period: 4.0,
+            amplitude: 1.0,
+            cycles: 5,
+        };
+        let gains = TuningRule::ZieglerNichols.gains(&point);
+        assert_close(gains.kp, 6.0, 1e-12);
+        assert_close(gains.ki, 3.0, 1e-12);
+        assert_close(gains.kd, 3.0, 1e-12);
+        let gains = TuningRule::TyreusLuyben.gains(&point);
+        assert_close(gains.kp, 10.0 / 2.2, 1e-12);
+        assert_close(gains.ki, 10.0 / 2.2 / 8.8, 1e-12);
+        assert_close(gains.kd, 10.0 / 2.2 * 4.0 / 6.3, 1e-12);
+        let gains = TuningRule::ZieglerNichols.gains(&point).in_time_unit(0.5);
+        assert_eq!(
+            gains,
+            Gains {
+                kp: 6.0,
+                ki: 1.5,
+                kd: 6.0
+            }
+        );
+    }
+
+    #[test]
+    fn step_response_metrics() {
+        let response = samples(&[
+            (0.0, 0.0),
+            (1.0, 0.0),
+            (2.0, 5.0),
+            (3.0, 11.0),
+            (4.0, 10.5),
+            (5.0, 10.0),
+            (6.0, 10.0),
+        ]);
+        let pushed = [None, Some(1.0), Some(1.0), Some(2.0)];
+        let metrics = analyze(&response, &pushed, 2.0, Some(10.0), 5.0).unwrap();
+        assert_eq!(metrics.initial, 0.0);
+        assert_eq!(metrics.last, 10.0);
+        assert_eq!(metrics.rise_time, Some(1.0));
+        assert_close(metrics.overshoot, 10.0, 1e-9);
+        assert_eq!(metrics.settling_time, Some(2.0));
+        assert_eq!(metrics.steady_state_error, 0.0);
+        assert_eq!(metrics.output_changes, 2);
+    }
+
+    #[test]
+    fn step_response_metrics_towards_the_last_value() {
+        let response = samples(&[(0.0, 0.0), (1.0, 2.0), (2.0, 4.0), (3.0, 6.0)]);
+        let metrics = analyze(&response, &[], 0.5, None, 5.0).unwrap();
+        assert_eq!(metrics.target, 6.0);
+        assert_eq!(metrics.rise_time, Some(2.0));
+        assert_eq!(metrics.overshoot, 0.0);
+        assert_eq!(metrics.settling_time, Some(2.5));
+        // never within the band of the target
+        let metrics = analyze(&response, &[], 0.5, Some(10.0), 5.0).unwrap();
+        assert_eq!(metrics.settling_time, None);
+        assert_eq!(metrics.rise_time, None);
+        assert_eq!(metrics.steady_state_error, 4.0);
+        assert!(analyze(&response, &[], 4.0, None, 5.0).is_none());
+    }
+
+    #[test]
+    fn relay_experiment_on_a_simulated_plant() {
+        // settles at 125°C with the fan stopped and at about 34°C at full speed
+        let pipeline: Pipeline = toml::from_str(
+            r#"
+            output = { Simulated = "plant" }
+            sample_rate = 1000
+
+            [input.Simulated]
+            heat_capacity = 50.0
+            passive_cooling = 0.2
+            fan_cooling = 2.0
+            sensor_time_constant = 5.0
+            load = { constant = 20.0 }
+            dt = 1.0
+
+            [[operations]]
+            type = "relay"
+            setpoint = 40.0
+            hysteresis = 0.5
+            "#,
+        )
+        .unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        let mut control = [pipeline
+            .into_builder_with_clock(clock.clone())
+            .build()
+            .unwrap()];
+        let mut input = Vec::new();
+        simulation::run_for(&mut control, clock.as_ref(), 900.0, |_, control, sample| {
+            input.push(control.state().last_input.unwrap_or(sample));
+        });
+        assert_eq!(input.len(), 901);
+
+        let point = relay_ultimate_point(&input, 40.0, 50.0, 0.5, 2).unwrap();
+        assert!(point.cycles >= 5, "{:?}", point);
+        // the lag of the sensor sets the pace of the oscillation
+        assert!(point.period > 5.0 && point.period < 100.0, "{:?}", point);
+        assert!(
+            point.amplitude > 0.5 && point.amplitude < 10.0,
+            "{:?}",
+            point
+        );
+        // the limit cycle repeats: both halves of the run measure the same point
+        let half = input.len() / 2;
+        let first = relay_ultimate_point(&input[..half], 40.0, 50.0, 0.5, 2).unwrap();
+        let second = relay_ultimate_point(&input[half..], 40.0, 50.0, 0.5, 0).unwrap();
+        assert_close(first.period, second.period, 0.05 * point.period);
+        assert_close(first.amplitude, second.amplitude, 0.05 * point.amplitude);
+        for rule in TuningRule::ALL.iter() {
+            let gains = rule.gains(&point);
+            assert!(gains.kp > 0.0 && gains.ki > 0.0 && gains.kd > 0.0);
+        }
+    }
+}
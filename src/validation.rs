@@ -0,0 +1,109 @@
+//! Semantic validation of configurations. Values which deserialize fine but make no sense (e.g.
+//! averaging over 0 samples, or clipping to an empty range) are reported with the path of the
+//! offending field, e.g. `pipelines.cpu.operations[2].n`, in the same notation as the
+//! errors of the parser, and by line once located in the text of a configuration (see
+//! [Config::from_str][crate::config::Config::from_str]).
+
+/// A problem with the value of a field of a configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// Path of the field, relative to whatever was validated (empty for the thing itself)
+    pub path: String,
+    pub message: String,
+    /// Line of the field in the text of the configuration, if known
+    pub line: Option<usize>,
+}
+
+impl ValidationError {
+    pub fn new<P: Into<String>, M: Into<String>>(path: P, message: M) -> ValidationError {
+        ValidationError {
+            path: path.into(),
+            message: message.into(),
+            line: None,
+        }
+    }
+
+    /// Make the path of the error relative to the parent of `prefix`, e.g. `n` within
+    /// `operations[2].Average` becomes `operations[2].n`.
+    pub fn within(mut self, prefix: &str) -> ValidationError {
+        self.path = if self.path.is_empty() {
+            prefix.to_string()
+        } else if self.path.starts_with('[') {
+            format!("{}{}", prefix, self.path)
+        } else {
+            format!("{}.{}", prefix, self.path)
+        };
+        self
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.path.is_empty(), self.line) {
+            (true, None) => write!(f, "{}", self.message),
+            (true, Some(line)) => write!(f, "line {}: {}", line, self.message),
+            (false, None) => write!(f, "{}: {}", self.path, self.message),
+            (false, Some(line)) => write!(f, "{} (line {}): {}", self.path, line, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Anything whose values can be checked beyond what deserialization already guarantees. Every
+/// registered operation implements it; the default implementation accepts anything.
+pub trait Validate {
+    /// All the problems found, each located by the path of its field.
+    fn validate(&self) -> Vec<ValidationError> {
+        Vec::new()
+    }
+}
+
+/// Collects the problems found while validating the fields of a value.
+#[derive(Default)]
+pub struct Checks {
+    errors: Vec<ValidationError>,
+}
+
+impl Checks {
+    pub fn new() -> Checks {
+        Checks::default()
+    }
+
+    /// Report a problem with `field` unless `ok` holds.
+    pub fn require(&mut self, ok: bool, field: &str, message: &str) -> &mut Checks {
+        if !ok {
+            self.errors.push(ValidationError::new(field, message));
+        }
+        self
+    }
+
+    /// `val` must be a number (not infinite nor NaN).
+    pub fn finite(&mut self, field: &str, val: f64) -> &mut Checks {
+        self.require(val.is_finite(), field, "must be a finite number")
+    }
+
+    /// `val` must be a number greater than zero.
+    pub fn positive(&mut self, field: &str, val: f64) -> &mut Checks {
+        self.require(
+            val.is_finite() && val > 0.0,
+            field,
+            "must be greater than 0",
+        )
+    }
+
+    /// `val` must be a number greater than or equal to zero.
+    pub fn non_negative(&mut self, field: &str, val: f64) -> &mut Checks {
+        self.require(val.is_finite() && val >= 0.0, field, "must not be negative")
+    }
+
+    /// `min` must not be greater than `max` (reported on `field`).
+    pub fn ordered(&mut self, field: &str, min: f64, max: f64) -> &mut Checks {
+        let message = format!("minimum ({}) is greater than maximum ({})", min, max);
+        self.require(min <= max, field, &message)
+    }
+
+    pub fn into_errors(self) -> Vec<ValidationError> {
+        self.errors
+    }
+}